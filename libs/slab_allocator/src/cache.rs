@@ -0,0 +1,289 @@
+/*  Object caches for the slab allocator
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::mem::size_of;
+use core::ptr;
+use spin::Mutex;
+
+use super::{PAGE_SIZE, page_source};
+
+/// Largest slab, in pages, as a power of two.
+const MAX_SLAB_ORDER: usize = 3;
+
+// Free objects are linked through their first word
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Slab header, kept at the end of the slab so that objects can start
+/// at the aligned base of the slab.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+    // Objects from this index on have never been handed out
+    untouched: usize,
+}
+
+struct SlabList {
+    head: *mut Slab,
+}
+
+impl SlabList {
+    const fn new() -> SlabList {
+        SlabList { head: 0 as *mut Slab }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        }
+        else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).next = ptr::null_mut();
+        (*slab).prev = ptr::null_mut();
+    }
+}
+
+/// Usage figures for one cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_in_use: usize,
+    pub slabs: usize,
+    pub pages: usize,
+}
+
+/// Cache of equally sized objects carved out of slabs. Allocation and
+/// freeing are O(1); at most one empty slab is kept back, the others
+/// are returned to the page source.
+pub struct Cache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    object_size: usize,
+    slab_order: usize,
+    objects_per_slab: usize,
+    partial: SlabList,
+    full: SlabList,
+    spare: *mut Slab,
+    objects_in_use: usize,
+    slabs: usize,
+}
+
+// Slabs are only reached through the cache, which sits behind a lock
+unsafe impl Send for Cache {}
+
+impl Cache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Cache {
+        Cache {
+            name: name,
+            size: size,
+            align: align,
+            object_size: 0,
+            slab_order: 0,
+            objects_per_slab: 0,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            spare: 0 as *mut Slab,
+            objects_in_use: 0,
+            slabs: 0,
+        }
+    }
+
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.slab_order
+    }
+
+    // Pick the object size and the smallest slab wasting at most 1/8
+    fn compute_layout(&mut self) {
+        assert!(self.align.is_power_of_two() && self.align <= PAGE_SIZE,
+            "Invalid alignment {} for cache {}", self.align, self.name);
+        let size = if self.size < size_of::<FreeObject>() { size_of::<FreeObject>() } else { self.size };
+        self.object_size = (size + self.align - 1) & !(self.align - 1);
+
+        self.slab_order = 0;
+        loop {
+            let usable = self.slab_bytes() - size_of::<Slab>();
+            let objects = usable / self.object_size;
+            let waste = self.slab_bytes() - objects * self.object_size;
+            if objects > 0 && (waste * 8 <= self.slab_bytes() || self.slab_order == MAX_SLAB_ORDER) {
+                self.objects_per_slab = objects;
+                break;
+            }
+            assert!(self.slab_order < MAX_SLAB_ORDER,
+                "Objects of {} bytes are too large for cache {}", self.object_size, self.name);
+            self.slab_order += 1;
+        }
+    }
+
+    unsafe fn slab_of(&self, object: *mut u8) -> *mut Slab {
+        let base = object as usize & !(self.slab_bytes() - 1);
+        (base + self.slab_bytes() - size_of::<Slab>()) as *mut Slab
+    }
+
+    unsafe fn slab_base(&self, slab: *mut Slab) -> usize {
+        slab as usize + size_of::<Slab>() - self.slab_bytes()
+    }
+
+    unsafe fn new_slab(&mut self) -> Option<*mut Slab> {
+        if !self.spare.is_null() {
+            let slab = self.spare;
+            self.spare = ptr::null_mut();
+            return Some(slab);
+        }
+
+        let base = match (page_source().alloc_block)(self.slab_order) {
+            Some(base) => base,
+            None => return None,
+        };
+        assert!(base & (self.slab_bytes() - 1) == 0,
+            "Page source returned a misaligned block at {:#x}", base);
+        let slab = (base + self.slab_bytes() - size_of::<Slab>()) as *mut Slab;
+        ptr::write(slab, Slab {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free: ptr::null_mut(),
+            in_use: 0,
+            untouched: 0,
+        });
+        self.slabs += 1;
+        Some(slab)
+    }
+
+    unsafe fn release_slab(&mut self, slab: *mut Slab) {
+        if self.spare.is_null() {
+            self.spare = slab;
+        }
+        else {
+            (page_source().free_block)(self.slab_base(slab), self.slab_order);
+            self.slabs -= 1;
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<*mut u8> {
+        if self.objects_per_slab == 0 {
+            self.compute_layout();
+        }
+        unsafe {
+            if self.partial.is_empty() {
+                match self.new_slab() {
+                    Some(slab) => self.partial.push(slab),
+                    None => return None,
+                }
+            }
+
+            let slab = self.partial.head;
+            let object = if !(*slab).free.is_null() {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                object as *mut u8
+            }
+            else {
+                let object = self.slab_base(slab) + (*slab).untouched * self.object_size;
+                (*slab).untouched += 1;
+                object as *mut u8
+            };
+
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.objects_per_slab {
+                self.partial.remove(slab);
+                self.full.push(slab);
+            }
+            self.objects_in_use += 1;
+            Some(object)
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, object: *mut u8) {
+        let slab = self.slab_of(object);
+        assert!((*slab).in_use > 0, "Freeing {:p} into empty slab of cache {}", object, self.name);
+
+        if (*slab).in_use == self.objects_per_slab {
+            self.full.remove(slab);
+            self.partial.push(slab);
+        }
+
+        let free = object as *mut FreeObject;
+        (*free).next = (*slab).free;
+        (*slab).free = free;
+        (*slab).in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if (*slab).in_use == 0 {
+            self.partial.remove(slab);
+            self.release_slab(slab);
+        }
+    }
+
+    /// Give the spare empty slab back to the page source.
+    pub fn shrink(&mut self) {
+        if !self.spare.is_null() {
+            unsafe {
+                (page_source().free_block)(self.slab_base(self.spare), self.slab_order);
+            }
+            self.spare = ptr::null_mut();
+            self.slabs -= 1;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            object_size: if self.object_size == 0 { self.size } else { self.object_size },
+            objects_in_use: self.objects_in_use,
+            slabs: self.slabs,
+            pages: self.slabs << self.slab_order,
+        }
+    }
+}
+
+/// Named cache for one kind of kernel object, usable from a `static`.
+pub struct ObjectCache {
+    cache: Mutex<Cache>,
+}
+
+impl ObjectCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> ObjectCache {
+        ObjectCache {
+            cache: Mutex::new(Cache::new(name, size, align)),
+        }
+    }
+
+    pub fn alloc(&self) -> Option<*mut u8> {
+        self.cache.lock().allocate()
+    }
+
+    pub unsafe fn free(&self, object: *mut u8) {
+        self.cache.lock().deallocate(object)
+    }
+
+    pub fn shrink(&self) {
+        self.cache.lock().shrink()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+}
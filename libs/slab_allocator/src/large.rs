@@ -0,0 +1,210 @@
+/*  Growable region for allocations too large for a size class
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::ptr;
+
+use super::{PAGE_SIZE, page_source};
+
+// Free runs are kept in address order, headed by their first page
+struct FreeRun {
+    pages: usize,
+    next: *mut FreeRun,
+}
+
+/// Page-granular first-fit heap over a virtual region. The region is
+/// mapped from its start up to a break that moves on demand, never
+/// beyond the configured limit.
+pub struct LargeHeap {
+    start: usize,
+    end: usize,
+    limit: usize,
+    free: *mut FreeRun,
+    pages_in_use: usize,
+}
+
+// Free runs are only reached through the heap, which sits behind a lock
+unsafe impl Send for LargeHeap {}
+
+impl LargeHeap {
+    pub const fn new() -> LargeHeap {
+        LargeHeap {
+            start: 0,
+            end: 0,
+            limit: 0,
+            free: 0 as *mut FreeRun,
+            pages_in_use: 0,
+        }
+    }
+
+    pub fn init(&mut self, start: usize, limit: usize) {
+        assert!(start % PAGE_SIZE == 0, "Heap start {:#x} is not page aligned", start);
+        self.start = start;
+        self.end = start;
+        self.limit = limit;
+    }
+
+    /// Change the largest size the region may grow to. Memory that is
+    /// mapped already stays mapped.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn mapped_pages(&self) -> usize {
+        (self.end - self.start) / PAGE_SIZE
+    }
+
+    pub fn pages_in_use(&self) -> usize {
+        self.pages_in_use
+    }
+
+    /// Size of the largest free run in the mapped part of the region.
+    pub fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run = self.free;
+        while !run.is_null() {
+            unsafe {
+                if (*run).pages > largest {
+                    largest = (*run).pages;
+                }
+                run = (*run).next;
+            }
+        }
+        largest * PAGE_SIZE
+    }
+
+    pub fn allocate(&mut self, pages: usize) -> Option<usize> {
+        if let Some(address) = self.first_fit(pages) {
+            self.pages_in_use += pages;
+            return Some(address);
+        }
+
+        // Grow the region, reusing a free run that reaches the break
+        let trailing = self.trailing_run_pages();
+        let grow = pages - trailing;
+        if self.end + grow * PAGE_SIZE > self.start + self.limit {
+            return None;
+        }
+        if !(page_source().map_pages)(self.end, grow) {
+            return None;
+        }
+        let old_end = self.end;
+        self.end += grow * PAGE_SIZE;
+        unsafe { self.insert_free(old_end, grow) };
+
+        let address = self.first_fit(pages).expect("Heap growth left no fitting run");
+        self.pages_in_use += pages;
+        Some(address)
+    }
+
+    pub unsafe fn deallocate(&mut self, address: usize, pages: usize) {
+        assert!(address >= self.start && address + pages * PAGE_SIZE <= self.end,
+            "Freeing {:#x} outside of the kernel heap", address);
+        self.pages_in_use -= pages;
+        self.insert_free(address, pages);
+        self.trim();
+    }
+
+    fn first_fit(&mut self, pages: usize) -> Option<usize> {
+        let mut prev: *mut FreeRun = ptr::null_mut();
+        let mut run = self.free;
+        unsafe {
+            while !run.is_null() {
+                if (*run).pages >= pages {
+                    let next = if (*run).pages > pages {
+                        let rest = (run as usize + pages * PAGE_SIZE) as *mut FreeRun;
+                        ptr::write(rest, FreeRun {
+                            pages: (*run).pages - pages,
+                            next: (*run).next,
+                        });
+                        rest
+                    }
+                    else {
+                        (*run).next
+                    };
+                    if prev.is_null() {
+                        self.free = next;
+                    }
+                    else {
+                        (*prev).next = next;
+                    }
+                    return Some(run as usize);
+                }
+                prev = run;
+                run = (*run).next;
+            }
+        }
+        None
+    }
+
+    fn trailing_run_pages(&self) -> usize {
+        let mut run = self.free;
+        unsafe {
+            while !run.is_null() {
+                if (*run).next.is_null() && run as usize + (*run).pages * PAGE_SIZE == self.end {
+                    return (*run).pages;
+                }
+                run = (*run).next;
+            }
+        }
+        0
+    }
+
+    unsafe fn insert_free(&mut self, address: usize, pages: usize) {
+        let mut prev: *mut FreeRun = ptr::null_mut();
+        let mut next = self.free;
+        while !next.is_null() && (next as usize) < address {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let run = address as *mut FreeRun;
+        ptr::write(run, FreeRun { pages: pages, next: next });
+        if prev.is_null() {
+            self.free = run;
+        }
+        else {
+            (*prev).next = run;
+        }
+
+        if !next.is_null() && address + pages * PAGE_SIZE == next as usize {
+            (*run).pages += (*next).pages;
+            (*run).next = (*next).next;
+        }
+        if !prev.is_null() && prev as usize + (*prev).pages * PAGE_SIZE == address {
+            (*prev).pages += (*run).pages;
+            (*prev).next = (*run).next;
+        }
+    }
+
+    // Give a free run at the break back to the page tables
+    unsafe fn trim(&mut self) {
+        let mut prev: *mut FreeRun = ptr::null_mut();
+        let mut run = self.free;
+        while !run.is_null() && !(*run).next.is_null() {
+            prev = run;
+            run = (*run).next;
+        }
+        if run.is_null() || run as usize + (*run).pages * PAGE_SIZE != self.end {
+            return;
+        }
+
+        let address = run as usize;
+        let pages = (*run).pages;
+        let next = (*run).next;
+        if (page_source().unmap_pages)(address, pages) {
+            if prev.is_null() {
+                self.free = next;
+            }
+            else {
+                (*prev).next = next;
+            }
+            self.end = address;
+        }
+    }
+}
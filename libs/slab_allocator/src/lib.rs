@@ -0,0 +1,292 @@
+#![feature(allocator)]
+
+// Only the kernel target takes its allocations from this heap. Host
+// builds, such as the kernel's unit tests, keep the system allocator.
+#![cfg_attr(target_os = "RustOS", allocator)]
+#![no_std]
+
+#![feature(const_fn)]
+
+extern crate spin;
+
+mod cache;
+mod large;
+mod sanitizer;
+
+pub use cache::{Cache, CacheStats, ObjectCache};
+
+use spin::Mutex;
+use large::LargeHeap;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Hooks through which the heap gets its memory. `alloc_block(order)`
+/// returns the virtual address of `2^order` pages aligned to their
+/// size, `free_block(address, order)` hands them back. `map_pages` and
+/// `unmap_pages` back or release `count` pages of the large object
+/// region at `address`, returning false if they could not.
+#[derive(Clone, Copy)]
+pub struct PageSource {
+    pub alloc_block: fn(usize) -> Option<usize>,
+    pub free_block: fn(usize, usize),
+    pub map_pages: fn(usize, usize) -> bool,
+    pub unmap_pages: fn(usize, usize) -> bool,
+}
+
+static PAGE_SOURCE: Mutex<Option<PageSource>> = Mutex::new(None);
+
+/// Connect the heap to its page source. Must be called before the
+/// first allocation; objects up to `MAX_SLAB_OBJECT` can be allocated
+/// from then on.
+pub fn init(source: PageSource) {
+    *PAGE_SOURCE.lock() = Some(source);
+}
+
+/// Place the region for large allocations at `heap_start`, growing up
+/// to `heap_limit` bytes.
+pub fn init_large_region(heap_start: usize, heap_limit: usize) {
+    LARGE_HEAP.lock().init(heap_start, heap_limit);
+}
+
+fn page_source() -> PageSource {
+    PAGE_SOURCE.lock().expect("Kernel heap used before initialization")
+}
+
+/// Change the ceiling of the large object region.
+pub fn set_heap_limit(heap_limit: usize) {
+    LARGE_HEAP.lock().set_limit(heap_limit);
+}
+
+/// State of the heap when an allocation could not be served.
+#[derive(Debug, Clone, Copy)]
+pub struct OomReport {
+    pub size: usize,
+    pub align: usize,
+    pub slab_pages: usize,
+    pub slab_bytes_in_use: usize,
+    pub large_pages_in_use: usize,
+    pub large_pages_mapped: usize,
+    pub heap_limit: usize,
+    pub largest_free_block: usize,
+}
+
+static OOM_HANDLER: Mutex<Option<fn(&OomReport) -> !>> = Mutex::new(None);
+
+/// Install the function called when the heap is exhausted.
+pub fn set_oom_handler(handler: fn(&OomReport) -> !) {
+    *OOM_HANDLER.lock() = Some(handler);
+}
+
+fn out_of_memory(size: usize, align: usize) -> ! {
+    let mut report = OomReport {
+        size: size,
+        align: align,
+        slab_pages: 0,
+        slab_bytes_in_use: 0,
+        large_pages_in_use: 0,
+        large_pages_mapped: 0,
+        heap_limit: 0,
+        largest_free_block: 0,
+    };
+    for_each_cache(|stats| {
+        report.slab_pages += stats.pages;
+        report.slab_bytes_in_use += stats.objects_in_use * stats.object_size;
+    });
+    {
+        let heap = LARGE_HEAP.lock();
+        report.large_pages_in_use = heap.pages_in_use();
+        report.large_pages_mapped = heap.mapped_pages();
+        report.heap_limit = heap.limit();
+        report.largest_free_block = heap.largest_free_run();
+    }
+
+    let handler = *OOM_HANDLER.lock();
+    match handler {
+        Some(handler) => handler(&report),
+        None => panic!("Kernel heap exhausted allocating {} bytes: {:?}", size, report),
+    }
+}
+
+const NUM_SIZE_CLASSES: usize = 9;
+
+/// Largest allocation served from the size-class caches.
+pub const MAX_SLAB_OBJECT: usize = 2048;
+
+static SIZE_CLASSES: [ObjectCache; NUM_SIZE_CLASSES] = [
+    ObjectCache::new("size-8", 8, 8),
+    ObjectCache::new("size-16", 16, 16),
+    ObjectCache::new("size-32", 32, 32),
+    ObjectCache::new("size-64", 64, 64),
+    ObjectCache::new("size-128", 128, 128),
+    ObjectCache::new("size-256", 256, 256),
+    ObjectCache::new("size-512", 512, 512),
+    ObjectCache::new("size-1024", 1024, 1024),
+    ObjectCache::new("size-2048", 2048, 2048),
+];
+
+/// Region serving allocations above `MAX_SLAB_OBJECT`.
+static LARGE_HEAP: Mutex<LargeHeap> = Mutex::new(LargeHeap::new());
+
+const MAX_NAMED_CACHES: usize = 16;
+
+static NAMED_CACHES: Mutex<[Option<&'static ObjectCache>; MAX_NAMED_CACHES]> =
+    Mutex::new([None; MAX_NAMED_CACHES]);
+
+/// Make a named cache show up in the heap statistics.
+pub fn register_cache(cache: &'static ObjectCache) {
+    for slot in NAMED_CACHES.lock().iter_mut() {
+        if slot.is_none() {
+            *slot = Some(cache);
+            return;
+        }
+    }
+    panic!("Too many named object caches");
+}
+
+/// Call `f` with the statistics of every size class and named cache.
+pub fn for_each_cache<F>(mut f: F) where F: FnMut(CacheStats) {
+    for cache in SIZE_CLASSES.iter() {
+        f(cache.stats());
+    }
+    for cache in NAMED_CACHES.lock().iter() {
+        if let Some(cache) = *cache {
+            f(cache.stats());
+        }
+    }
+}
+
+/// Pages the heap holds from its page source: every slab, plus the
+/// mapped part of the large object region.
+pub fn heap_pages() -> usize {
+    let mut pages = 0;
+    for_each_cache(|stats| pages += stats.pages);
+    pages + LARGE_HEAP.lock().mapped_pages()
+}
+
+/// Number of pages used by allocations too large for a size class.
+pub fn large_pages() -> usize {
+    LARGE_HEAP.lock().pages_in_use()
+}
+
+// Index of the size class serving a request, if any
+fn size_class(size: usize, align: usize) -> Option<usize> {
+    let size = if size < align { align } else { size };
+    if size > MAX_SLAB_OBJECT {
+        return None;
+    }
+    let mut class = 0;
+    while (8 << class) < size {
+        class += 1;
+    }
+    Some(class)
+}
+
+// Pages for an allocation too large for the size classes. Runs of
+// pages are only page aligned, so larger alignments cannot be served.
+fn large_pages_for(size: usize, align: usize) -> Option<usize> {
+    if align > PAGE_SIZE {
+        return None;
+    }
+    Some((size + PAGE_SIZE - 1) / PAGE_SIZE)
+}
+
+fn allocate(size: usize, align: usize) -> Option<*mut u8> {
+    match size_class(size, align) {
+        Some(class) => SIZE_CLASSES[class].alloc(),
+        None => large_pages_for(size, align)
+            .and_then(|pages| LARGE_HEAP.lock().allocate(pages))
+            .map(|address| address as *mut u8),
+    }
+}
+
+unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    match size_class(size, align) {
+        Some(class) => SIZE_CLASSES[class].free(ptr),
+        None => {
+            let pages = large_pages_for(size, align).expect("Freeing an allocation the heap never made");
+            LARGE_HEAP.lock().deallocate(ptr as usize, pages);
+        }
+    }
+}
+
+// Debug builds go through the sanitizer, which wraps every allocation
+// in red zones and delays the reuse of freed memory.
+
+#[cfg(target_os = "RustOS")]
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let ptr = if cfg!(debug_assertions) {
+        sanitizer::allocate(size, align)
+    }
+    else {
+        allocate(size, align)
+    };
+    match ptr {
+        Some(ptr) => ptr,
+        None => out_of_memory(size, align),
+    }
+}
+
+#[cfg(target_os = "RustOS")]
+#[no_mangle]
+pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    if cfg!(debug_assertions) {
+        unsafe { sanitizer::deallocate(ptr, size, align) };
+    }
+    else {
+        unsafe { deallocate(ptr, size, align) };
+    }
+}
+
+#[cfg(target_os = "RustOS")]
+#[no_mangle]
+pub extern fn __rust_usable_size(size: usize, align: usize) -> usize {
+    if cfg!(debug_assertions) {
+        return size;
+    }
+    match size_class(size, align) {
+        Some(class) => 8 << class,
+        None => large_pages_for(size, align).map_or(size, |pages| PAGE_SIZE * pages),
+    }
+}
+
+#[cfg(target_os = "RustOS")]
+#[no_mangle]
+pub extern fn __rust_reallocate_inplace(ptr: *mut u8, size: usize,
+    new_size: usize, align: usize) -> usize
+{
+    if cfg!(debug_assertions) {
+        unsafe { sanitizer::check(ptr, size, align, "realloc") };
+        return size;
+    }
+    // Only growth within the same size class or run of pages keeps its place
+    if __rust_usable_size(size, align) == __rust_usable_size(new_size, align) {
+        __rust_usable_size(size, align)
+    }
+    else {
+        size
+    }
+}
+
+#[cfg(target_os = "RustOS")]
+#[no_mangle]
+pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
+                                align: usize) -> *mut u8 {
+    use core::{ptr, cmp};
+
+    if cfg!(debug_assertions) {
+        return match unsafe { sanitizer::reallocate(ptr, size, new_size, align) } {
+            Some(new_ptr) => new_ptr,
+            None => out_of_memory(new_size, align),
+        };
+    }
+
+    if __rust_usable_size(size, align) == __rust_usable_size(new_size, align) {
+        return ptr;
+    }
+
+    let new_ptr = __rust_allocate(new_size, align);
+    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
+    __rust_deallocate(ptr, size, align);
+    new_ptr
+}
@@ -0,0 +1,197 @@
+/*  Heap sanitizer for debug builds
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+//! Every allocation gets a header and red zones on both sides, and its
+//! contents start out poisoned. Freed blocks are poisoned again and sit
+//! in a quarantine before they go back to the heap. Red zones are
+//! checked on free and realloc, the free poison when a block leaves the
+//! quarantine. Corruption panics with the serial number, size and
+//! address of the allocation it was found in.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::{cmp, ptr, slice};
+use spin::Mutex;
+
+use super::{allocate as raw_allocate, deallocate as raw_deallocate};
+
+/// Bytes of red zone after every allocation. The red zone in front is
+/// at least as large, padded up to the alignment.
+const REDZONE_SIZE: usize = 16;
+
+const LIVE_MAGIC: usize = 0x11ae_a110_c8ed_11ae;
+const FREED_MAGIC: usize = 0xdead_f8ee_d8ed_dead;
+
+const REDZONE_BYTE: u8 = 0xfc;
+const ALLOC_POISON: u8 = 0xa5;
+const FREE_POISON: u8 = 0x6b;
+
+/// Freed blocks held back from reuse.
+const QUARANTINE_SLOTS: usize = 64;
+
+// Kept at the start of every block, before the front red zone
+struct Header {
+    magic: usize,
+    size: usize,
+    serial: usize,
+}
+
+static NEXT_SERIAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Clone, Copy)]
+struct Quarantined {
+    user: usize,
+    size: usize,
+    align: usize,
+}
+
+struct Quarantine {
+    slots: [Option<Quarantined>; QUARANTINE_SLOTS],
+    next: usize,
+}
+
+impl Quarantine {
+    // Queue a freed block, returning the oldest one once the quarantine is full
+    fn push(&mut self, block: Quarantined) -> Option<Quarantined> {
+        let oldest = self.slots[self.next].take();
+        self.slots[self.next] = Some(block);
+        self.next = (self.next + 1) % QUARANTINE_SLOTS;
+        oldest
+    }
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    slots: [None; QUARANTINE_SLOTS],
+    next: 0,
+});
+
+fn block_align(align: usize) -> usize {
+    cmp::max(align, size_of::<usize>())
+}
+
+// Header plus front red zone, keeping the user pointer aligned
+fn front_size(align: usize) -> usize {
+    let align = block_align(align);
+    (size_of::<Header>() + REDZONE_SIZE + align - 1) / align * align
+}
+
+fn block_size(size: usize, align: usize) -> usize {
+    front_size(align) + size + REDZONE_SIZE
+}
+
+unsafe fn header<'a>(user: usize, align: usize) -> &'a mut Header {
+    &mut *((user - front_size(align)) as *mut Header)
+}
+
+unsafe fn fill(start: usize, len: usize, byte: u8) {
+    ptr::write_bytes(start as *mut u8, byte, len);
+}
+
+// Offset of the first byte in the range that is not `byte`
+unsafe fn first_mismatch(start: usize, len: usize, byte: u8) -> Option<usize> {
+    slice::from_raw_parts(start as *const u8, len).iter().position(|&b| b != byte)
+}
+
+fn corruption(what: &str, offset: isize, user: usize, header: &Header) -> ! {
+    panic!("Heap corruption: {} at offset {} of allocation #{} ({} bytes at {:#x})",
+           what, offset, header.serial, header.size, user);
+}
+
+// Check the red zones around a block, relative to the user pointer
+unsafe fn check_redzones(user: usize, align: usize, header: &Header) {
+    let redzone_start = user - front_size(align) + size_of::<Header>();
+    let front = user - redzone_start;
+    if let Some(offset) = first_mismatch(redzone_start, front, REDZONE_BYTE) {
+        corruption("front red zone overwritten", offset as isize - front as isize, user, header);
+    }
+    if let Some(offset) = first_mismatch(user + header.size, REDZONE_SIZE, REDZONE_BYTE) {
+        corruption("back red zone overwritten", (header.size + offset) as isize, user, header);
+    }
+}
+
+/// Check that `ptr` is a live allocation of `size` bytes with intact
+/// red zones. `operation` names the caller in the report.
+pub unsafe fn check(ptr: *mut u8, size: usize, align: usize, operation: &str) {
+    let user = ptr as usize;
+    let header = header(user, align);
+    if header.magic == FREED_MAGIC {
+        panic!("Heap corruption: {} of allocation #{} ({} bytes at {:#x}), which was already freed",
+               operation, header.serial, header.size, user);
+    }
+    if header.magic != LIVE_MAGIC {
+        panic!("Heap corruption: {} of {:#x}, which is not a heap allocation or has its header overwritten",
+               operation, user);
+    }
+    if header.size != size {
+        panic!("Heap corruption: {} of allocation #{} ({} bytes at {:#x}) with size {}",
+               operation, header.serial, header.size, user, size);
+    }
+    check_redzones(user, align, header);
+}
+
+pub fn allocate(size: usize, align: usize) -> Option<*mut u8> {
+    let block = match raw_allocate(block_size(size, align), block_align(align)) {
+        Some(block) => block as usize,
+        None => return None,
+    };
+    let user = block + front_size(align);
+    unsafe {
+        ptr::write(block as *mut Header, Header {
+            magic: LIVE_MAGIC,
+            size: size,
+            serial: NEXT_SERIAL.fetch_add(1, Ordering::Relaxed),
+        });
+        fill(block + size_of::<Header>(), user - block - size_of::<Header>(), REDZONE_BYTE);
+        fill(user, size, ALLOC_POISON);
+        fill(user + size, REDZONE_SIZE, REDZONE_BYTE);
+    }
+    Some(user as *mut u8)
+}
+
+/// Poison a block and put it into quarantine, releasing the oldest
+/// quarantined block after checking it was left alone.
+pub unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    check(ptr, size, align, "free");
+    let user = ptr as usize;
+    header(user, align).magic = FREED_MAGIC;
+    fill(user, size, FREE_POISON);
+
+    let oldest = QUARANTINE.lock().push(Quarantined {
+        user: user,
+        size: size,
+        align: align,
+    });
+    if let Some(block) = oldest {
+        release(block);
+    }
+}
+
+// Hand a block leaving the quarantine back to the heap
+unsafe fn release(block: Quarantined) {
+    let header = header(block.user, block.align);
+    if header.magic != FREED_MAGIC || header.size != block.size {
+        panic!("Heap corruption: header of freed block at {:#x} overwritten", block.user);
+    }
+    if let Some(offset) = first_mismatch(block.user, block.size, FREE_POISON) {
+        corruption("write after free", offset as isize, block.user, header);
+    }
+    check_redzones(block.user, block.align, header);
+    raw_deallocate((block.user - front_size(block.align)) as *mut u8,
+                   block_size(block.size, block.align), block_align(block.align));
+}
+
+/// Move an allocation to a new block. Sanitized allocations never grow
+/// in place, so that the red zones always follow the requested size.
+pub unsafe fn reallocate(ptr: *mut u8, size: usize, new_size: usize,
+                         align: usize) -> Option<*mut u8> {
+    check(ptr, size, align, "realloc");
+    let new_ptr = match allocate(new_size, align) {
+        Some(new_ptr) => new_ptr,
+        None => return None,
+    };
+    ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(size, new_size));
+    deallocate(ptr, size, align);
+    Some(new_ptr)
+}
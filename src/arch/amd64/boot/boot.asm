@@ -60,7 +60,7 @@ enable_paging:
 set_page_tables:
     mov eax, p3_table
     or eax, 0b11 ; present + writable
-    mov [p4_table], eax ; Identity mapping, to be removed.
+    mov [p4_table], eax ; Identity mapping, dropped by mem2::init_mem
     mov [p4_table + 256 * 8], eax ; Higher half mapping
 
     mov eax, p2_table
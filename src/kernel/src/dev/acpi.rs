@@ -0,0 +1,152 @@
+/*  ACPI table lookup
+ *  Written by Andrew Jianzhong Liu
+ *  All Rights Reserved
+ */
+
+use core::{mem, ptr};
+use multiboot2::BootInformation;
+use mem2::{self, CacheType, MmioRegion, PhysicalAddress};
+
+// Multiboot2 tags holding a copy of the RSDP
+const TAG_END: u32 = 0;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+// Offsets of the root table addresses in the RSDP
+const RSDP_RSDT: usize = 16;
+const RSDP_LENGTH: usize = 20;
+const RSDP_XSDT: usize = 24;
+// The ACPI 1.0 part of the RSDP, which has a checksum of its own
+const RSDP_V1_SIZE: usize = 20;
+
+/// Length of the header every table starts with.
+pub const HEADER_SIZE: usize = 36;
+
+/// System description table mapped into the kernel half. Unmapped when
+/// dropped.
+pub struct AcpiTable {
+    region: Option<MmioRegion>,
+}
+
+impl AcpiTable {
+    // Map the table at `physical`, whose header gives its length
+    fn map(physical: PhysicalAddress) -> Option<AcpiTable> {
+        let header = match unsafe { mem2::map_mmio(physical, HEADER_SIZE, CacheType::WriteBack) } {
+            Ok(header) => AcpiTable { region: Some(header) },
+            Err(_) => return None,
+        };
+        let length = header.read::<u32>(4) as usize;
+        drop(header);
+        if length < HEADER_SIZE {
+            return None;
+        }
+        match unsafe { mem2::map_mmio(physical, length, CacheType::WriteBack) } {
+            Ok(region) => Some(AcpiTable { region: Some(region) }),
+            Err(_) => None,
+        }
+    }
+
+    fn region(&self) -> &MmioRegion {
+        self.region.as_ref().unwrap()
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.read(0)
+    }
+
+    /// Length in bytes, header included.
+    pub fn len(&self) -> usize {
+        self.region().size()
+    }
+
+    /// Field at `offset`. Fields of ACPI tables need not be aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= self.len(),
+            "Field at {:#x} outside ACPI table of {:#x} bytes", offset, self.len());
+        unsafe { ptr::read_unaligned((self.region().base() + offset) as *const T) }
+    }
+
+    // All bytes of a valid table add up to zero
+    fn checksum_ok(&self) -> bool {
+        (0..self.len()).fold(0u8, |sum, i| sum.wrapping_add(self.read::<u8>(i))) == 0
+    }
+}
+
+impl Drop for AcpiTable {
+    fn drop(&mut self) {
+        if let Some(region) = self.region.take() {
+            mem2::unmap_mmio(region);
+        }
+    }
+}
+
+/// Find the table with `signature`, such as `b"APIC"` for the MADT,
+/// through the RSDP the boot loader passed along. The RSDP, the root
+/// table and the table found must all have valid checksums.
+pub fn find_table(boot_info: &BootInformation, signature: &[u8; 4]) -> Option<AcpiTable> {
+    let (root_address, entry_size) = match root_table(boot_info) {
+        Some(root) => root,
+        None => return None,
+    };
+    let root = match AcpiTable::map(root_address) {
+        Some(root) => root,
+        None => return None,
+    };
+    if !root.checksum_ok() {
+        return None;
+    }
+    for i in 0..((root.len() - HEADER_SIZE) / entry_size) {
+        let offset = HEADER_SIZE + i * entry_size;
+        let address = if entry_size == 8 {
+            root.read::<u64>(offset) as PhysicalAddress
+        }
+        else {
+            root.read::<u32>(offset) as PhysicalAddress
+        };
+        if let Some(table) = AcpiTable::map(address) {
+            if &table.signature() == signature && table.checksum_ok() {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+// Whether the `length` bytes at `address` add up to zero
+unsafe fn checksum_ok(address: usize, length: usize) -> bool {
+    (0..length).fold(0u8, |sum, i| sum.wrapping_add(ptr::read((address + i) as *const u8))) == 0
+}
+
+// Address of the root table and the size of its entries. The XSDT of
+// ACPI 2.0 is preferred over the RSDT. Copies of the RSDP with a bad
+// checksum are ignored.
+fn root_table(boot_info: &BootInformation) -> Option<(PhysicalAddress, usize)> {
+    let mut rsdt = None;
+    let mut address = boot_info.start_address() + 8;
+    while address + 8 <= boot_info.end_address() {
+        let (typ, size) = unsafe { (ptr::read(address as *const u32),
+                                    ptr::read((address + 4) as *const u32)) };
+        // The RSDP copy follows the tag header
+        let rsdp = address + 8;
+        match typ {
+            TAG_END => break,
+            TAG_ACPI_NEW => unsafe {
+                let length = ptr::read_unaligned((rsdp + RSDP_LENGTH) as *const u32) as usize;
+                if length >= RSDP_XSDT + 8 && rsdp + length <= address + size as usize
+                    && checksum_ok(rsdp, RSDP_V1_SIZE) && checksum_ok(rsdp, length) {
+                    let xsdt = ptr::read_unaligned((rsdp + RSDP_XSDT) as *const u64);
+                    return Some((xsdt as PhysicalAddress, 8));
+                }
+            },
+            TAG_ACPI_OLD => unsafe {
+                if checksum_ok(rsdp, RSDP_V1_SIZE) {
+                    let table = ptr::read_unaligned((rsdp + RSDP_RSDT) as *const u32);
+                    rsdt = Some((table as PhysicalAddress, 4));
+                }
+            },
+            _ => {}
+        }
+        address += (size as usize + 7) & !7;
+    }
+    rsdt
+}
@@ -0,0 +1,339 @@
+/*  Local APIC and I/O APIC driver
+ *  Written by Andrew Jianzhong Liu
+ *  All Rights Reserved
+ */
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use multiboot2::BootInformation;
+use x86_64::registers::msr::{rdmsr, wrmsr};
+use mem2::{self, CacheType, MmioRegion, PhysicalAddress};
+use util::without_interrupts;
+use super::*;
+use super::pic::{self, PIC_1_OFFSET, IRQ_COUNT};
+
+/// Vector the local APIC raises for spurious interrupts. These get no
+/// end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// MADT layout
+const MADT_LAPIC_ADDRESS: usize = 36;
+const MADT_ENTRIES: usize = 44;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LAPIC_ADDRESS: u8 = 5;
+
+// Polarity and trigger mode of a source override, in its flags. Both
+// fields being all ones means active low and level triggered.
+const OVERRIDE_POLARITY: u16 = 0b11;
+const OVERRIDE_TRIGGER: u16 = 0b11 << 2;
+
+// Local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_ISR: usize = 0x100;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_SIZE: usize = 0x400;
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+// I/O APIC registers, reached through a select and a window register
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+struct LocalApic {
+    region: MmioRegion,
+}
+
+impl LocalApic {
+    fn id(&self) -> u8 {
+        (self.region.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+
+    // Whether `vector` was delivered by this APIC and is being serviced
+    fn in_service(&self, vector: u8) -> bool {
+        let register = LAPIC_ISR + (vector as usize / 32) * 0x10;
+        self.region.read::<u32>(register) & 1 << (vector % 32) != 0
+    }
+
+    fn end_of_interrupt(&mut self) {
+        self.region.write::<u32>(LAPIC_EOI, 0);
+    }
+
+    fn send_ipi(&mut self, apic_id: u8, vector: u8) {
+        // The write to the low half sends it
+        self.region.write::<u32>(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+        self.region.write::<u32>(LAPIC_ICR_LOW, vector as u32 | ICR_ASSERT);
+        while self.region.read::<u32>(LAPIC_ICR_LOW) & ICR_PENDING != 0 {}
+    }
+}
+
+struct IoApic {
+    region: MmioRegion,
+    /// First global system interrupt of the chip.
+    gsi_base: u32,
+    lines: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        self.region.write::<u32>(IOREGSEL, register);
+        self.region.read::<u32>(IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.region.write::<u32>(IOREGSEL, register);
+        self.region.write::<u32>(IOWIN, value);
+    }
+
+    fn redirection(&mut self, line: u32) -> u64 {
+        let low = self.read(IOAPIC_REDIRECTION + line * 2) as u64;
+        let high = self.read(IOAPIC_REDIRECTION + line * 2 + 1) as u64;
+        low | high << 32
+    }
+
+    fn set_redirection(&mut self, line: u32, entry: u64) {
+        self.write(IOAPIC_REDIRECTION + line * 2 + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REDIRECTION + line * 2, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.lines
+    }
+}
+
+// Global system interrupt an ISA IRQ arrives on, with the polarity and
+// trigger bits of its redirection entry
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    flags: u64,
+    overridden: bool,
+}
+
+struct Apics {
+    local: LocalApic,
+    io: Vec<IoApic>,
+    routes: [IsaRoute; IRQ_COUNT],
+}
+
+impl Apics {
+    // I/O APIC and line an ISA IRQ arrives on
+    fn io_apic(&mut self, irq: u8) -> Option<(&mut IoApic, u32)> {
+        let gsi = self.routes[irq as usize].gsi;
+        self.io.iter_mut().find(|io| io.handles(gsi)).map(|io| {
+            let line = gsi - io.gsi_base;
+            (io, line)
+        })
+    }
+
+    // Whether the line of `irq` was given to another IRQ by a source
+    // override, like IRQ 2 when the timer is moved to GSI 2
+    fn displaced(&self, irq: usize) -> bool {
+        let route = self.routes[irq];
+        !route.overridden && (0..IRQ_COUNT).any(|other| {
+            other != irq && self.routes[other].overridden && self.routes[other].gsi == route.gsi
+        })
+    }
+
+    fn set_masked(&mut self, irq: u8, masked: bool) {
+        if self.displaced(irq as usize) {
+            return;
+        }
+        if let Some((io, line)) = self.io_apic(irq) {
+            let entry = io.redirection(line);
+            io.set_redirection(line, if masked {
+                entry | REDIRECT_MASKED
+            } else {
+                entry & !REDIRECT_MASKED
+            });
+        }
+    }
+}
+
+static APICS: Mutex<Option<Apics>> = Mutex::new(None);
+static APIC_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+// Redirection bits for the flags of a source override. ISA interrupts
+// are active high and edge triggered unless overridden.
+fn override_flags(flags: u16) -> u64 {
+    let mut redirect = 0;
+    if flags & OVERRIDE_POLARITY == OVERRIDE_POLARITY {
+        redirect |= REDIRECT_ACTIVE_LOW;
+    }
+    if flags & OVERRIDE_TRIGGER == OVERRIDE_TRIGGER {
+        redirect |= REDIRECT_LEVEL;
+    }
+    redirect
+}
+
+/// Switch from the 8259 PICs to the APICs the MADT lists, if there are
+/// any. The ISA IRQs keep their vectors and stay masked until unmasked.
+/// Returns false if the PICs stay in use. Must run before any IRQ line
+/// is unmasked.
+pub fn init_apic(boot_info: &BootInformation) -> bool {
+    let madt = match super::acpi::find_table(boot_info, b"APIC") {
+        Some(madt) => madt,
+        None => return false,
+    };
+
+    let mut lapic_address = madt.read::<u32>(MADT_LAPIC_ADDRESS) as PhysicalAddress;
+    let mut io_apics = Vec::new();
+    let mut routes = [IsaRoute { gsi: 0, flags: 0, overridden: false }; IRQ_COUNT];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let length = madt.read::<u8>(offset + 1) as usize;
+        if length < 2 {
+            break;
+        }
+        match madt.read::<u8>(offset) {
+            ENTRY_IO_APIC => {
+                io_apics.push((madt.read::<u32>(offset + 4) as PhysicalAddress,
+                               madt.read::<u32>(offset + 8)));
+            }
+            ENTRY_SOURCE_OVERRIDE => {
+                let source = madt.read::<u8>(offset + 3) as usize;
+                if source < IRQ_COUNT {
+                    routes[source] = IsaRoute {
+                        gsi: madt.read::<u32>(offset + 4),
+                        flags: override_flags(madt.read::<u16>(offset + 8)),
+                        overridden: true,
+                    };
+                }
+            }
+            ENTRY_LAPIC_ADDRESS => {
+                lapic_address = madt.read::<u64>(offset + 4) as PhysicalAddress;
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    drop(madt);
+    if io_apics.is_empty() {
+        return false;
+    }
+
+    let local = match unsafe { mem2::map_mmio(lapic_address, LAPIC_SIZE, CacheType::Uncached) } {
+        Ok(region) => LocalApic { region: region },
+        Err(_) => return false,
+    };
+    let mut apics = Apics {
+        local: local,
+        io: Vec::with_capacity(io_apics.len()),
+        routes: routes,
+    };
+    for &(address, gsi_base) in io_apics.iter() {
+        let region = match unsafe { mem2::map_mmio(address, IOAPIC_SIZE, CacheType::Uncached) } {
+            Ok(region) => region,
+            Err(_) => continue,
+        };
+        let mut io = IoApic { region: region, gsi_base: gsi_base, lines: 0 };
+        io.lines = ((io.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for line in 0..io.lines {
+            io.set_redirection(line, REDIRECT_MASKED);
+        }
+        apics.io.push(io);
+    }
+
+    without_interrupts(|| {
+        pic::disable_pic();
+        unsafe {
+            wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE);
+        }
+        apics.local.region.write::<u32>(LAPIC_TPR, 0);
+        apics.local.region.write::<u32>(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        // Every IRQ goes to this CPU, masked for now
+        let destination = (apics.local.id() as u64) << 56;
+        for irq in 0..IRQ_COUNT {
+            if apics.displaced(irq) {
+                continue;
+            }
+            let route = apics.routes[irq];
+            if let Some((io, line)) = apics.io_apic(irq as u8) {
+                let vector = (PIC_1_OFFSET as usize + irq) as u64;
+                io.set_redirection(line, vector | route.flags | REDIRECT_MASKED | destination);
+            }
+        }
+        *APICS.lock() = Some(apics);
+        APIC_ENABLED.store(true, Ordering::SeqCst);
+    });
+    true
+}
+
+/// Whether IRQs come through the APICs rather than the 8259 PICs.
+pub fn apic_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Whether `irq` came from the masked 8259 PICs rather than an I/O APIC.
+/// They can still raise spurious IRQ 7 and IRQ 15, which the local APIC
+/// does not track and which must get no end of interrupt. Only to be
+/// called from the handler of `irq`.
+pub fn check_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    match APICS.lock().as_ref() {
+        Some(apics) => !apics.local.in_service(PIC_1_OFFSET + irq),
+        None => false,
+    }
+}
+
+/// Stop the ISA IRQ `irq` from being raised. IRQs are routed through
+/// the source overrides, and an IRQ whose line was given to another
+/// one is left alone.
+pub fn mask_irq(irq: u8) {
+    without_interrupts(|| {
+        if let Some(apics) = APICS.lock().as_mut() {
+            apics.set_masked(irq, true);
+        }
+    });
+}
+
+pub fn unmask_irq(irq: u8) {
+    without_interrupts(|| {
+        if let Some(apics) = APICS.lock().as_mut() {
+            apics.set_masked(irq, false);
+        }
+    });
+}
+
+/// Tell the local APIC the current interrupt was handled. Only to be
+/// called from interrupt handlers.
+pub fn end_of_interrupt() {
+    if let Some(apics) = APICS.lock().as_mut() {
+        apics.local.end_of_interrupt();
+    }
+}
+
+/// APIC ID of this CPU.
+pub fn local_apic_id() -> Option<u8> {
+    without_interrupts(|| APICS.lock().as_ref().map(|apics| apics.local.id()))
+}
+
+/// Raise `vector` on the CPU with local APIC `apic_id`, waiting until
+/// the interrupt is delivered.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    without_interrupts(|| {
+        let mut apics = APICS.lock();
+        let apics = apics.as_mut().expect("Inter-processor interrupt without a local APIC");
+        apics.local.send_ipi(apic_id, vector);
+    });
+}
@@ -0,0 +1,210 @@
+/*  ATA disk driver, polled PIO with 28-bit LBA
+ *  Written by Andrew Jianzhong Liu
+ *  All Rights Reserved
+ */
+
+use super::*;
+use super::block::{BlockDevice, BlockError};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Largest sector count a single LBA28 command takes
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+// Status polls before a drive is given up on
+const POLL_LIMIT: usize = 1_000_000;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Primary,
+    Secondary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+pub struct AtaDrive {
+    data: Port<u16>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_select: Port<u8>,
+    // Status on read, command on write
+    command: Port<u8>,
+    alt_status: Port<u8>,
+    slave: bool,
+    sectors: usize,
+}
+
+impl AtaDrive {
+    /// Look for an ATA disk at the given position. ATAPI devices and
+    /// empty positions give `None`.
+    pub fn probe(bus: Bus, drive: Drive) -> Option<AtaDrive> {
+        let (base, control) = match bus {
+            Bus::Primary => (0x1f0, 0x3f6),
+            Bus::Secondary => (0x170, 0x376),
+        };
+        let mut ata = unsafe {
+            AtaDrive {
+                data: Port::new(base),
+                sector_count: Port::new(base + 2),
+                lba_low: Port::new(base + 3),
+                lba_mid: Port::new(base + 4),
+                lba_high: Port::new(base + 5),
+                drive_select: Port::new(base + 6),
+                command: Port::new(base + 7),
+                alt_status: Port::new(control),
+                slave: drive == Drive::Slave,
+                sectors: 0,
+            }
+        };
+
+        ata.select(0);
+        ata.sector_count.write(0);
+        ata.lba_low.write(0);
+        ata.lba_mid.write(0);
+        ata.lba_high.write(0);
+        ata.command.write(CMD_IDENTIFY);
+        if ata.command.read() == 0 {
+            return None;
+        }
+        if ata.wait_not_busy().is_err() {
+            return None;
+        }
+        // Packet devices set the signature in the LBA registers
+        if ata.lba_mid.read() != 0 || ata.lba_high.read() != 0 {
+            return None;
+        }
+        if ata.wait_data().is_err() {
+            return None;
+        }
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = ata.data.read();
+        }
+        ata.sectors = identify[60] as usize | (identify[61] as usize) << 16;
+        if ata.sectors == 0 { None } else { Some(ata) }
+    }
+
+    // Select the drive with the top bits of `lba`, then give it the
+    // customary 400ns to settle
+    fn select(&mut self, lba: usize) {
+        let slave = if self.slave { 1 << 4 } else { 0 };
+        self.drive_select.write(0xe0 | slave | ((lba >> 24) & 0x0f) as u8);
+        for _ in 0..4 {
+            self.alt_status.read();
+        }
+    }
+
+    fn wait_not_busy(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    // Wait until the drive has a sector to transfer
+    fn wait_data(&mut self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::DeviceError);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn start_command(&mut self, lba: usize, count: usize, command: u8) -> Result<(), BlockError> {
+        self.wait_not_busy()?;
+        self.select(lba);
+        // A count of 0 asks for 256 sectors
+        self.sector_count.write((count % MAX_SECTORS_PER_COMMAND) as u8);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+        self.command.write(command);
+        Ok(())
+    }
+
+    fn check_range(&self, start: usize, len: usize) -> Result<usize, BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::Misaligned);
+        }
+        let count = len / SECTOR_SIZE;
+        if start + count > self.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(count)
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> usize {
+        self.sectors
+    }
+
+    fn read_blocks(&mut self, start: usize, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = self.check_range(start, buffer.len())?;
+        let mut done = 0;
+        while done < count {
+            let batch = ::core::cmp::min(count - done, MAX_SECTORS_PER_COMMAND);
+            self.start_command(start + done, batch, CMD_READ_SECTORS)?;
+            for sector in done..(done + batch) {
+                self.wait_data()?;
+                for pair in buffer[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE].chunks_mut(2) {
+                    let word = self.data.read();
+                    pair[0] = word as u8;
+                    pair[1] = (word >> 8) as u8;
+                }
+            }
+            done += batch;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: usize, buffer: &[u8]) -> Result<(), BlockError> {
+        let count = self.check_range(start, buffer.len())?;
+        let mut done = 0;
+        while done < count {
+            let batch = ::core::cmp::min(count - done, MAX_SECTORS_PER_COMMAND);
+            self.start_command(start + done, batch, CMD_WRITE_SECTORS)?;
+            for sector in done..(done + batch) {
+                self.wait_data()?;
+                for pair in buffer[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE].chunks(2) {
+                    self.data.write(pair[0] as u16 | (pair[1] as u16) << 8);
+                }
+            }
+            done += batch;
+        }
+        self.command.write(CMD_CACHE_FLUSH);
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::DeviceError);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+/*  Block device interface
+ *  Written by Andrew Jianzhong Liu
+ *  All Rights Reserved
+ */
+
+/// Why a block transfer failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The transfer runs past the end of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    Misaligned,
+    /// The device reported an error.
+    DeviceError,
+    /// The device did not answer in time.
+    Timeout,
+}
+
+/// Device storing data in fixed size blocks, addressed by block number.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> usize;
+
+    /// Fill `buffer` from the blocks starting at `start`.
+    fn read_blocks(&mut self, start: usize, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buffer` to the blocks starting at `start`.
+    fn write_blocks(&mut self, start: usize, buffer: &[u8]) -> Result<(), BlockError>;
+}
@@ -9,6 +9,11 @@ pub mod console;
 #[macro_use]
 pub mod clock;
 pub mod floppy;
+pub mod block;
+pub mod ata;
+pub mod pic;
+pub mod acpi;
+pub mod apic;
 
 use spin::Mutex;
 
@@ -101,12 +106,6 @@ impl<T: InOut> UnsafePort<T> {
     }
 }
 
-struct Pic {
-    offset: u8,
-    command: UnsafePort<u8>,
-    data: UnsafePort<u8>,
-}
-
 pub fn init_io(){
     let mut status: isize = 0;
     status = (status << 1) | keyboard::init_kbd();
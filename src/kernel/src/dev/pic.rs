@@ -0,0 +1,172 @@
+/*  8259 programmable interrupt controller driver
+ *  Written by Andrew Jianzhong Liu
+ *  All Rights Reserved
+ */
+
+use super::*;
+use util::without_interrupts;
+
+/// Vectors of IRQ 0 - 7 and IRQ 8 - 15, right above the exceptions.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const IRQ_COUNT: usize = 16;
+
+// The slave is wired to line 2 of the master
+const CASCADE_IRQ: u8 = 2;
+// Lowest priority line of each chip, where spurious interrupts show up
+const SPURIOUS_LINE: u8 = 7;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+struct Pic {
+    offset: u8,
+    command: UnsafePort<u8>,
+    data: UnsafePort<u8>,
+}
+
+impl Pic {
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(OCW2_EOI);
+    }
+
+    // Lines being serviced
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(OCW3_READ_ISR);
+        self.command.read()
+    }
+}
+
+struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+}
+
+impl ChainedPics {
+    // Chip serving `irq`, and the line on it
+    fn pic(&mut self, irq: u8) -> (&mut Pic, u8) {
+        assert!((irq as usize) < IRQ_COUNT, "No IRQ {}", irq);
+        if irq < 8 { (&mut self.master, irq) } else { (&mut self.slave, irq - 8) }
+    }
+}
+
+static PICS: Mutex<ChainedPics> = Mutex::new(unsafe {
+    ChainedPics {
+        master: Pic {
+            offset: PIC_1_OFFSET,
+            command: UnsafePort::new(0x20),
+            data: UnsafePort::new(0x21),
+        },
+        slave: Pic {
+            offset: PIC_2_OFFSET,
+            command: UnsafePort::new(0xa0),
+            data: UnsafePort::new(0xa1),
+        },
+    }
+});
+
+/// Move the IRQs off the exception vectors to `PIC_1_OFFSET` and up,
+/// with every line but the cascade masked.
+pub fn init_pic() {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let pics = &mut *pics;
+        // Writes to an unused port give the chips time to take each word
+        let mut wait_port: UnsafePort<u8> = unsafe { UnsafePort::new(0x80) };
+        let mut wait = || unsafe { wait_port.write(0) };
+        unsafe {
+            pics.master.command.write(ICW1_INIT | ICW1_ICW4);
+            wait();
+            pics.slave.command.write(ICW1_INIT | ICW1_ICW4);
+            wait();
+            pics.master.data.write(pics.master.offset);
+            wait();
+            pics.slave.data.write(pics.slave.offset);
+            wait();
+            pics.master.data.write(1 << CASCADE_IRQ);
+            wait();
+            pics.slave.data.write(CASCADE_IRQ);
+            wait();
+            pics.master.data.write(ICW4_8086);
+            wait();
+            pics.slave.data.write(ICW4_8086);
+            wait();
+
+            pics.master.data.write(!(1 << CASCADE_IRQ));
+            pics.slave.data.write(0xff);
+        }
+    });
+}
+
+/// Mask every line, for when the APICs take over. The chips stay
+/// remapped, so that anything they still raise misses the exception
+/// vectors.
+pub fn disable_pic() {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.master.data.write(0xff);
+            pics.slave.data.write(0xff);
+        }
+    });
+}
+
+/// Stop `irq` from being raised.
+pub fn mask_irq(irq: u8) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let (pic, line) = pics.pic(irq);
+        unsafe {
+            let mask = pic.data.read();
+            pic.data.write(mask | 1 << line);
+        }
+    });
+}
+
+pub fn unmask_irq(irq: u8) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let (pic, line) = pics.pic(irq);
+        unsafe {
+            let mask = pic.data.read();
+            pic.data.write(mask & !(1 << line));
+        }
+    });
+}
+
+/// Whether `irq` is a spurious interrupt, which shows up on IRQ 7 or
+/// IRQ 15 when a line drops before it is acknowledged. Spurious
+/// interrupts must not get an end of interrupt, but for IRQ 15 the
+/// master did see a real one on the cascade line, which this sends.
+/// Only to be called from the handler of `irq`.
+pub fn check_spurious(irq: u8) -> bool {
+    let mut pics = PICS.lock();
+    let pics = &mut *pics;
+    if irq == SPURIOUS_LINE {
+        return unsafe { pics.master.in_service() } & 1 << SPURIOUS_LINE == 0;
+    }
+    if irq == SPURIOUS_LINE + 8 {
+        let spurious = unsafe { pics.slave.in_service() } & 1 << SPURIOUS_LINE == 0;
+        if spurious {
+            unsafe { pics.master.end_of_interrupt() };
+        }
+        return spurious;
+    }
+    false
+}
+
+/// Tell the chips `irq` was handled. IRQs of the slave need an end of
+/// interrupt on both chips.
+pub fn end_of_interrupt(irq: u8) {
+    let mut pics = PICS.lock();
+    let pics = &mut *pics;
+    unsafe {
+        if irq >= 8 {
+            pics.slave.end_of_interrupt();
+        }
+        pics.master.end_of_interrupt();
+    }
+}
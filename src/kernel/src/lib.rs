@@ -1,6 +1,10 @@
 #![feature(alloc, lang_items, asm, unique, const_fn, naked_functions, core_intrinsics, abi_x86_interrupt)]
-#![no_std]
+// Unit tests run on the host, with std and its allocator
+#![cfg_attr(not(test), no_std)]
 
+// Only no_std brings in core by itself
+#[cfg(test)]
+extern crate core;
 extern crate rlibc;
 extern crate volatile;
 extern crate spin;
@@ -13,15 +17,14 @@ extern crate x86_64;
 extern crate bitflags;
 extern crate bit_field;
 
-/* Temporary heap allocator crate */
-extern crate hole_allocator;
+/* Kernel heap allocator crate */
+extern crate slab_allocator;
 #[macro_use]
 extern crate alloc;
 
 #[macro_use]
 pub mod dev;
 pub mod fs;
-pub mod mem;
 pub mod procs;
 pub mod trap;
 pub mod util;
@@ -125,6 +128,7 @@ fn log_status(msg:&str, res: Result<(),isize>){
     println!("");
 }
 
+#[allow(dead_code)]
 fn sys_halt(code: usize) -> ! {
     println!("Code {}",code);
     log("System halted.");
@@ -145,25 +149,53 @@ pub extern fn rust_start(mb_info_addr: usize){
     util::enable_write_protect_bit();
 
     // Set up new expandable page table and remap the kernel
-    let mut mem_ctrl = mem2::init_mem(&boot_info);
+    let mem_ctrl = mem2::init_mem(&boot_info);
+    mem2::print_meminfo();
+
+    // Swap to the primary master disk, if one is attached and set up with mkswap
+    if let Some(disk) = dev::ata::AtaDrive::probe(dev::ata::Bus::Primary, dev::ata::Drive::Master) {
+        let status = mem2::enable_swap(alloc::boxed::Box::new(disk)).map(|_| ()).map_err(|_| 1);
+        log_status("Swap area initialization", status);
+    }
 
     // Have to reload the boot info
     
 
     // Initialize trap handlers
-    //trap::init_trap(&mut mem_ctrl);
+    trap::init_trap(&mem_ctrl);
+
+    // The 8259 PICs stay in use on machines without APICs
+    if dev::apic::init_apic(&boot_info) {
+        log_status("Local APIC and I/O APIC initialization", Ok(()));
+    }
+    else {
+        log_status("No APIC found, 8259 PIC initialization", Ok(()));
+    }
 
     // Initialize all drivers
-    //dev::init_io();
+    dev::init_io();
+    trap::enable_interrupts();
 
     // Initialize file system
     //fs::init_fs();
 
-    sys_halt(0);
+    idle();
+}
+
+// Nothing else runs yet, so the idle time goes to zeroing frames. Each
+// batch is short, so interrupts are not held up by a full refill.
+fn idle() -> ! {
+    loop {
+        if mem2::refill_zero_pool(mem2::ZERO_POOL_BATCH) == 0 {
+            unsafe { asm!("hlt" :::: "volatile") };
+        }
+    }
 }
 
+#[cfg(not(test))]
 #[lang = "eh_personality"] extern fn eh_personality() {}
 
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str,
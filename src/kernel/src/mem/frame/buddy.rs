@@ -1,11 +0,0 @@
-/*  Buddy Frame allocator for TiOS
- *  Author: Jianzhong Liu
- *  All rights reserved
- */
-
-// TODO: Implement a buddy frame allocator
-
-pub struct BuddyFrameAllocator {
-
-}
-
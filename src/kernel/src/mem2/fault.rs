@@ -0,0 +1,212 @@
+/*  Page fault handling module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::fmt;
+use super::*;
+use super::page::table::entries::*;
+use super::vma::{find_vma, VMA_WRITE, VMA_USER, VMA_EXEC};
+
+/// Decoded page fault: the address from CR2 and the error code bits.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub address: VirtualAddress,
+    pub protection_violation: bool,
+    pub write: bool,
+    pub user: bool,
+    pub reserved_bit: bool,
+    pub instruction_fetch: bool,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}: {} {} on {} page",
+               self.address,
+               if self.user { "user" } else { "kernel" },
+               if self.instruction_fetch { "fetch" } else if self.write { "write" } else { "read" },
+               if self.protection_violation { "present" } else { "missing" })?;
+        if self.reserved_bit {
+            write!(f, ", reserved bit set")?;
+        }
+        if self.address < PAGE_SIZE {
+            write!(f, ", null pointer access")?;
+        }
+        else if self.address < USER_START {
+            write!(f, ", access to the unmapped low memory area")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy)]
+pub enum FaultError {
+    /// The address is below `USER_START`, which is never mapped so
+    /// that null and small pointers are caught.
+    NullPointer,
+    /// The address is in the guard page below the stack of the named owner.
+    StackOverflow(&'static str),
+    /// The address is in no region, or in one whose pages are only
+    /// mapped by its owner.
+    Unmapped,
+    /// The page is present but the access is not allowed.
+    ProtectionViolation,
+    /// A page table entry has a reserved bit set.
+    MalformedTable,
+    /// The region does not allow this kind of access.
+    AccessDenied(Vma),
+    /// No frame was left to back the page.
+    OutOfFrames(Vma),
+    /// The fault hit while the page tables or regions were locked.
+    TablesLocked,
+    /// No frame was left to copy a copy-on-write page into.
+    CopyOutOfFrames,
+    /// A swapped out page could not be read back.
+    SwapIn(SwapError),
+}
+
+// Whether the flags of a region allow the faulting access
+fn permits(vma: &Vma, fault: &PageFault) -> bool {
+    (!fault.write || vma.flags.contains(VMA_WRITE))
+        && (!fault.user || vma.flags.contains(VMA_USER))
+        && (!fault.instruction_fetch || vma.flags.contains(VMA_EXEC))
+}
+
+/// Try to resolve a page fault by backing a page of an anonymous
+/// region, copying a copy-on-write page or reading a page from swap.
+pub fn handle_page_fault(fault: &PageFault) -> Result<(), FaultError> {
+    if fault.reserved_bit {
+        return Err(FaultError::MalformedTable);
+    }
+    if fault.address < USER_START {
+        return Err(FaultError::NullPointer);
+    }
+    if let Some(owner) = super::stack::guard_owner(fault.address) {
+        return Err(FaultError::StackOverflow(owner));
+    }
+    if fault.protection_violation && fault.write && resolve_copy_on_write(fault)? {
+        return Ok(());
+    }
+    if !fault.protection_violation && resolve_swapped(fault)? {
+        return Ok(());
+    }
+    let vma = match find_vma(fault.address)? {
+        Some(vma) => vma,
+        None if fault.protection_violation => return Err(FaultError::ProtectionViolation),
+        None => return Err(FaultError::Unmapped),
+    };
+    if fault.protection_violation || !permits(&vma, fault) {
+        return Err(FaultError::AccessDenied(vma));
+    }
+    if vma.backing != Backing::Anonymous {
+        return Err(FaultError::Unmapped);
+    }
+
+    let mut table_guard = match ACTIVE_TABLE.try_lock() {
+        Some(guard) => guard,
+        None => return Err(FaultError::TablesLocked),
+    };
+    let active_table = match table_guard.as_mut() {
+        Some(active_table) => active_table,
+        None => return Err(FaultError::TablesLocked),
+    };
+
+    let page = Page::from(fault.address);
+    // Another access may have backed the page in the meantime
+    if active_table.translate_page(page).is_some() {
+        return Ok(());
+    }
+
+    let frame = match GlobalFrameAllocator.take_zeroed_frame() {
+        Some(frame) => frame,
+        None => match super::swap::allocate_frame(active_table) {
+            Some(frame) => {
+                zero_frame(&frame);
+                frame
+            }
+            None => return Err(FaultError::OutOfFrames(vma)),
+        },
+    };
+    active_table.map_to(page, frame, vma.flags.entry_flags(), &mut GlobalFrameAllocator);
+    Ok(())
+}
+
+/// Read a swapped out page back in. Returns false if the page is not
+/// swapped out.
+fn resolve_swapped(fault: &PageFault) -> Result<bool, FaultError> {
+    let mut table_guard = match ACTIVE_TABLE.try_lock() {
+        Some(guard) => guard,
+        None => return Err(FaultError::TablesLocked),
+    };
+    let active_table = match table_guard.as_mut() {
+        Some(active_table) => active_table,
+        None => return Err(FaultError::TablesLocked),
+    };
+
+    let page = Page::from(fault.address);
+    let flags = match active_table.entry_mut(page).and_then(|entry| entry.swap_slot()) {
+        Some((_, flags)) => flags,
+        None => return Ok(false),
+    };
+    if (fault.user && !flags.contains(USER_ACCESSIBLE)) || (fault.write && !flags.contains(WRITABLE))
+        || (fault.instruction_fetch && flags.contains(NO_EXECUTE)) {
+        return Err(FaultError::ProtectionViolation);
+    }
+    super::swap::swap_in(active_table, page).map_err(FaultError::SwapIn)
+}
+
+/// Give a write to a copy-on-write page its own frame. The last
+/// holder of a shared frame takes it over without copying. Returns
+/// false if the page is not copy-on-write.
+fn resolve_copy_on_write(fault: &PageFault) -> Result<bool, FaultError> {
+    let mut table_guard = match ACTIVE_TABLE.try_lock() {
+        Some(guard) => guard,
+        None => return Err(FaultError::TablesLocked),
+    };
+    let active_table = match table_guard.as_mut() {
+        Some(active_table) => active_table,
+        None => return Err(FaultError::TablesLocked),
+    };
+
+    let page = Page::from(fault.address);
+    let (frame, mut flags) = {
+        let entry = match active_table.entry_mut(page) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let flags = entry.flags();
+        if !flags.contains(COPY_ON_WRITE) || (fault.user && !flags.contains(USER_ACCESSIBLE)) {
+            return Ok(false);
+        }
+        (entry.pointed_frame().expect("Copy-on-write page without a frame"), flags)
+    };
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(WRITABLE);
+
+    let shared = GlobalFrameAllocator::with_coremap(|coremap| {
+        coremap.contains(&frame) && coremap.refcount(&frame) > 1
+    });
+    if shared {
+        // Cold pages may be pushed out to make room, but never this
+        // one, as swapping skips shared frames
+        let copy = match super::swap::allocate_frame(active_table) {
+            Some(copy) => copy,
+            None => return Err(FaultError::CopyOutOfFrames),
+        };
+        unsafe {
+            ::core::ptr::copy_nonoverlapping(phys_to_virt(frame.start_address()) as *const u8,
+                                             phys_to_virt(copy.start_address()) as *mut u8,
+                                             PAGE_SIZE);
+        }
+        record_mapping(&copy, page.start_address());
+        active_table.entry_mut(page).unwrap().set(copy, flags);
+        // Drops this address space's reference to the shared frame
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+    else {
+        active_table.entry_mut(page).unwrap().set(frame, flags);
+    }
+    flush_tlb(page.start_address());
+    Ok(true)
+}
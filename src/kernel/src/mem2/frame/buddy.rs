@@ -0,0 +1,324 @@
+/*  Buddy frame allocator module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use super::*;
+use super::coremap::*;
+
+use multiboot2;
+
+/// Number of block orders kept by the allocator. The largest block
+/// holds `1 << (MAX_ORDER - 1)` frames (4 MiB).
+pub const MAX_ORDER: usize = 11;
+
+/// Buddy allocator keeping one free list per order. The lists are
+/// threaded through the core map entries of the first frame of each
+/// free block, so the allocator needs no memory of its own.
+pub struct BuddyAllocator {
+    coremap: CoreMap,
+    free_lists: [Option<usize>; MAX_ORDER],
+    free_frames: usize,
+}
+
+// Convert Initial Allocator to Core map allocator
+
+impl BuddyAllocator {
+    pub fn new(init_frame_alloc: InitialFrameAllocator,
+            active_table: &mut ActivePageTable,
+            boot_info: &multiboot2::BootInformation,
+            ) -> BuddyAllocator {
+        let mut init_frame_alloc = init_frame_alloc;
+        let mem_sections_tag = boot_info.memory_map_tag().expect("Memory map required.");
+        let mem_sections = mem_sections_tag.memory_areas();
+
+        let cm = unsafe{CoreMap::new(mem_sections.clone(), active_table, &mut init_frame_alloc)};
+
+        let mut allocator = BuddyAllocator {
+            coremap: cm,
+            free_lists: [None; MAX_ORDER],
+            free_frames: 0,
+        };
+
+        // Everything below the initial allocator's cursor has been handed
+        // out already and stays allocated to the kernel.
+        let first_free = init_frame_alloc.next_frame.number;
+        let reserved = init_frame_alloc.reserved;
+        for area in mem_sections {
+            let area_start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let area_end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            let mut start = if area_start > first_free { area_start } else { first_free };
+
+            for range in reserved.iter() {
+                let (reserved_start, reserved_end) = (range.start / PAGE_SIZE, range.end / PAGE_SIZE);
+                if reserved_start >= area_end {
+                    break;
+                }
+                if start < reserved_start {
+                    allocator.release_range(start, reserved_start);
+                }
+                if start < reserved_end {
+                    start = reserved_end;
+                }
+            }
+            if start < area_end {
+                allocator.release_range(start, area_end);
+            }
+        }
+
+        // Frames returned to the initial allocator during remapping
+        for pos in init_frame_alloc.freed_frames.iter_mut() {
+            if let Some(number) = pos.take() {
+                allocator.deallocate_frame(Frame{ number: number });
+            }
+        }
+
+        allocator
+    }
+
+    /// Number of frames currently sitting in the free lists.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn coremap(&self) -> &CoreMap {
+        &self.coremap
+    }
+
+    pub fn coremap_mut(&mut self) -> &mut CoreMap {
+        &mut self.coremap
+    }
+
+    // Push a free block onto the list for its order
+    fn push_block(&mut self, number: usize, order: usize) {
+        self.coremap[number] = CoreMapEntry::new_free_head(order, self.free_lists[order]);
+        self.free_lists[order] = Some(number);
+        self.free_frames += 1 << order;
+    }
+
+    // Pop a free block off the list for an order
+    fn pop_block(&mut self, order: usize) -> Option<usize> {
+        match self.free_lists[order] {
+            Some(number) => {
+                self.free_lists[order] = self.coremap[number].free_next();
+                self.coremap.set(Frame{ number: number }, IS_ALLOCATED | IS_KERNEL_FRAME, 0);
+                self.free_frames -= 1 << order;
+                Some(number)
+            }
+            None => None,
+        }
+    }
+
+    // Unlink a specific free block from the list for its order
+    fn remove_block(&mut self, number: usize, order: usize) {
+        let next = self.coremap[number].free_next();
+        if self.free_lists[order] == Some(number) {
+            self.free_lists[order] = next;
+        }
+        else {
+            let mut current = self.free_lists[order].expect("Free block missing from its list");
+            loop {
+                let current_next = self.coremap[current].free_next();
+                if current_next == Some(number) {
+                    self.coremap[current] = CoreMapEntry::new_free_head(order, next);
+                    break;
+                }
+                current = current_next.expect("Free block missing from its list");
+            }
+        }
+        self.coremap.set_unused(Frame{ number: number });
+        self.free_frames -= 1 << order;
+    }
+
+    fn is_free_block(&self, number: usize, order: usize) -> bool {
+        number < self.coremap.size() && {
+            let entry = self.coremap[number];
+            entry.is_free_head() && entry.free_order() == order
+        }
+    }
+
+    // Allocate a block of 2^order frames, splitting larger blocks as needed
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let mut current_order = order;
+        while current_order < MAX_ORDER && self.free_lists[current_order].is_none() {
+            current_order += 1;
+        }
+        if current_order == MAX_ORDER {
+            return None;
+        }
+
+        let number = self.pop_block(current_order).unwrap();
+        while current_order > order {
+            current_order -= 1;
+            self.push_block(number + (1 << current_order), current_order);
+        }
+        for i in 1..(1 << order) {
+            self.coremap.set(Frame{ number: number + i }, IS_ALLOCATED | IS_KERNEL_FRAME, 0);
+        }
+        Some(number)
+    }
+
+    // Free a block of 2^order frames, merging it with its buddies
+    fn free_block(&mut self, number: usize, order: usize) {
+        for i in 0..(1 << order) {
+            self.coremap.set_unused(Frame{ number: number + i });
+        }
+
+        let mut number = number;
+        let mut order = order;
+        while order + 1 < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove_block(buddy, order);
+            if buddy < number {
+                number = buddy;
+            }
+            order += 1;
+        }
+        self.push_block(number, order);
+    }
+
+    // Free the frames in [start, end) as the largest aligned blocks possible
+    fn release_range(&mut self, start: usize, end: usize) {
+        let mut number = start;
+        while number < end {
+            let mut order = 0;
+            while order + 1 < MAX_ORDER
+                && number % (1 << (order + 1)) == 0
+                && number + (1 << (order + 1)) <= end {
+                order += 1;
+            }
+            self.free_block(number, order);
+            number += 1 << order;
+        }
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_block(0).map(|number| Frame{ number: number })
+    }
+
+    fn allocate_contiguous_frames(&mut self, num:usize) -> Option<FrameIter> {
+        if num == 0 {
+            return None;
+        }
+        let order = (0..MAX_ORDER).find(|&order| (1 << order) >= num);
+        match order.and_then(|order| self.allocate_block(order).map(|number| (number, order))) {
+            Some((number, order)) => {
+                // Give back the tail of the block that was not asked for
+                self.release_range(number + num, number + (1 << order));
+                Some(FrameIter::new(Frame{ number: number },
+                                    Frame{ number: number + num - 1 }))
+            }
+            None => None,
+        }
+    }
+
+    // Drops one reference; the frame is only freed with the last one
+    fn deallocate_frame(&mut self, frame: Frame){
+        assert!(self.coremap[frame.number].flags().contains(IS_ALLOCATED),
+            "Freeing unallocated frame {:?}", frame);
+        if self.coremap.put_ref(&frame) > 0 {
+            return;
+        }
+        self.free_block(frame.number, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::*;
+
+    // Allocator owning frames 0 up to `frames`, all of them free
+    fn buddy(frames: usize) -> BuddyAllocator {
+        let entries = vec![CoreMapEntry::new_zero(); frames].into_boxed_slice();
+        let mut allocator = BuddyAllocator {
+            coremap: CoreMap::from_entries(unsafe { &mut *Box::into_raw(entries) }),
+            free_lists: [None; MAX_ORDER],
+            free_frames: 0,
+        };
+        allocator.release_range(0, frames);
+        allocator
+    }
+
+    #[test]
+    fn release_range_builds_largest_blocks() {
+        let allocator = buddy((1 << (MAX_ORDER - 1)) + 3);
+        assert_eq!(allocator.free_frames(), (1 << (MAX_ORDER - 1)) + 3);
+        assert_eq!(allocator.free_lists[MAX_ORDER - 1], Some(0));
+        assert_eq!(allocator.free_lists[1], Some(1 << (MAX_ORDER - 1)));
+        assert_eq!(allocator.free_lists[0], Some((1 << (MAX_ORDER - 1)) + 2));
+    }
+
+    #[test]
+    fn allocation_splits_and_free_coalesces() {
+        let mut allocator = buddy(16);
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.number, 0);
+        assert_eq!(allocator.free_frames(), 15);
+        for order in 0..4 {
+            assert!(allocator.is_free_block(1 << order, order));
+        }
+        assert_eq!(allocator.free_lists[4], None);
+
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.free_frames(), 16);
+        assert_eq!(allocator.free_lists[4], Some(0));
+        for order in 0..4 {
+            assert_eq!(allocator.free_lists[order], None);
+        }
+    }
+
+    #[test]
+    fn contiguous_allocation_returns_tail() {
+        let mut allocator = buddy(16);
+        let frames: Vec<Frame> = allocator.allocate_contiguous_frames(3).unwrap().collect();
+        assert_eq!(frames.iter().map(|frame| frame.number).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(allocator.free_frames(), 13);
+        assert!(allocator.is_free_block(3, 0));
+
+        for frame in frames {
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.free_frames(), 16);
+        assert_eq!(allocator.free_lists[4], Some(0));
+    }
+
+    #[test]
+    fn exhausted_allocator_returns_none() {
+        let mut allocator = buddy(2);
+        assert!(allocator.allocate_frame().is_some());
+        assert!(allocator.allocate_frame().is_some());
+        assert!(allocator.allocate_frame().is_none());
+        assert!(allocator.allocate_contiguous_frames(1).is_none());
+    }
+
+    #[test]
+    fn shared_frame_freed_with_last_reference() {
+        let mut allocator = buddy(4);
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.coremap_mut().get_ref(&frame), 2);
+
+        allocator.deallocate_frame(Frame { number: frame.number });
+        assert_eq!(allocator.free_frames(), 3);
+        assert!(allocator.coremap().owner(&frame).is_some());
+
+        allocator.deallocate_frame(Frame { number: frame.number });
+        assert_eq!(allocator.free_frames(), 4);
+        assert!(allocator.coremap().owner(&frame).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_panics() {
+        let mut allocator = buddy(4);
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(Frame { number: frame.number });
+        allocator.deallocate_frame(frame);
+    }
+}
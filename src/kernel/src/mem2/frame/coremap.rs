@@ -1,4 +1,4 @@
-/*  Core map module 
+/*  Core map module
  *  Author: Andrew Jianzhong Liu
  *  All rights reserved
  */
@@ -11,7 +11,7 @@ bitflags! {
     flags CoreMapFlags: u8 {
         const IS_ALLOCATED = 1 << 0,
         const IS_KERNEL_FRAME = 1 << 1,
-
+        const IS_FREE_HEAD = 1 << 2,
     }
 }
 
@@ -20,21 +20,98 @@ bitflags! {
 pub struct CoreMapEntry(u64);
 
 // Bits:
-// 16 - 63: vma w/o upper 16 bits
+// 28 - 63: page number the frame is mapped at, w/o upper 16 bits
+// 16 - 27: reference count
 // 0: is allocated
 // 1: is kernel frame
-// 2 - 7 : reserved
-// 8 - 15: pid 
+// 2: is head of a free buddy block
+// 3 - 7 : order of the free buddy block
+// 8 - 15: pid
+// Free block heads use bits 16 - 63 to link to the next free block.
+
+/// Marks the end of a free list stored in the core map.
+const FREE_LIST_END: usize = 0xffff_ffff_ffff;
+
+/// Largest reference count an entry can hold.
+pub const MAX_REFCOUNT: usize = 0xfff;
 
 impl CoreMapEntry {
+    /// Entry for a frame mapped at `vma`. Allocated frames start out
+    /// with a single reference.
     pub fn new(vma: VirtualAddress, flags: CoreMapFlags, pid: u8) -> CoreMapEntry{
-        let entry = ((vma as u64) << 16) | (flags.bits() as u64) | ((pid as u64) << 8);
+        let refcount = if flags.contains(IS_ALLOCATED) { 1 } else { 0 };
+        let entry = ((((vma as u64) >> 12) & 0xf_ffff_ffff) << 28) | ((refcount as u64) << 16)
+            | (flags.bits() as u64) | ((pid as u64) << 8);
         CoreMapEntry(entry)
     }
 
     pub fn new_zero() -> CoreMapEntry {
         CoreMapEntry(0x0)
     }
+
+    // Entry for the first frame of a free block, linking to the next block
+    pub fn new_free_head(order: usize, next: Option<usize>) -> CoreMapEntry {
+        assert!(order < 32, "Buddy order {} does not fit in a core map entry", order);
+        let next = next.unwrap_or(FREE_LIST_END) as u64;
+        CoreMapEntry((next << 16) | ((order as u64) << 3) | (IS_FREE_HEAD.bits() as u64))
+    }
+
+    pub fn flags(&self) -> CoreMapFlags {
+        CoreMapFlags::from_bits_truncate(self.0 as u8)
+    }
+
+    pub fn pid(&self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn refcount(&self) -> usize {
+        ((self.0 >> 16) & 0xfff) as usize
+    }
+
+    /// Virtual address the frame was last mapped at, if any.
+    pub fn vma(&self) -> Option<VirtualAddress> {
+        match (self.0 >> 28) as usize {
+            0 => None,
+            page_number => {
+                let vma = page_number << 12;
+                // Sign extend to a canonical address
+                if vma & (1 << 47) != 0 {
+                    Some(vma | 0xffff_0000_0000_0000)
+                }
+                else {
+                    Some(vma)
+                }
+            }
+        }
+    }
+
+    pub fn is_free_head(&self) -> bool {
+        self.flags().contains(IS_FREE_HEAD)
+    }
+
+    pub fn free_order(&self) -> usize {
+        ((self.0 >> 3) & 0x1f) as usize
+    }
+
+    pub fn free_next(&self) -> Option<usize> {
+        match (self.0 >> 16) as usize {
+            FREE_LIST_END => None,
+            next => Some(next),
+        }
+    }
+
+    fn set_refcount(&mut self, refcount: usize) {
+        assert!(refcount <= MAX_REFCOUNT, "Core map reference count overflow");
+        self.0 = (self.0 & !(0xfff << 16)) | ((refcount as u64) << 16);
+    }
+
+    fn set_vma(&mut self, vma: VirtualAddress) {
+        self.0 = (self.0 & 0xfff_ffff) | ((((vma as u64) >> 12) & 0xf_ffff_ffff) << 28);
+    }
+
+    fn set_pid(&mut self, pid: u8) {
+        self.0 = (self.0 & !(0xff << 8)) | ((pid as u64) << 8);
+    }
 }
 
 pub struct CoreMap {
@@ -46,7 +123,7 @@ use core::ops::{Index, IndexMut};
 
 impl Index<usize> for CoreMap {
     type Output = CoreMapEntry;
-    
+
     fn index(&self, index:usize) -> &CoreMapEntry {
         assert!(index < self.size, "Core map indexing out of bounds with index {} in size {}.", index, self.size);
         &self.entries[index]
@@ -61,21 +138,21 @@ impl IndexMut<usize> for CoreMap {
     }
 }
 
-use super::super::page::table::entries::WRITABLE;
+use super::super::page::table::entries::{WRITABLE, NO_EXECUTE};
 
 impl CoreMap {
+    /// Build a core map covering every frame up to the highest usable
+    /// address. The entries are mapped at `COREMAP_VMA` and start out
+    /// as allocated kernel frames; the frame allocator releases the
+    /// frames it owns afterwards.
     pub unsafe fn new<A>(mem_areas: multiboot2::MemoryAreaIter,
                          active_table: &mut ActivePageTable,
                          allocator: &mut A)
-                         -> CoreMap 
+                         -> CoreMap
                          where A: FrameAllocator {
 
-        for area in mem_areas.clone(){
-            println!("{:?}",area);
-        }
-
         let frame_num = mem_areas.map(|area| area.base_addr + area.length - 1)
-            .max().unwrap() as usize / PAGE_SIZE;
+            .max().unwrap() as usize / PAGE_SIZE + 1;
         let entry_bytes = frame_num * 8;
         let entry_frame_num = {
             let mut base = entry_bytes / PAGE_SIZE;
@@ -85,43 +162,226 @@ impl CoreMap {
             base
         };
 
-        println!("Top frame number = {}, size in bytes = {}, size in frames: {}", frame_num, entry_bytes, entry_frame_num);
+        #[cfg(debug_assertions)]
+        {
+            println!("Core map: {} frames, {} bytes in {} frames", frame_num, entry_bytes, entry_frame_num);
+        }
 
-        let first_frame = allocator.allocate_frame().expect("No remaining frames");
-        let map_addr = first_frame.start_address();
-        println!("Initial Frame: {:?}", first_frame);
-        
-        let mut new_frame = Frame::from(0);
-        for i in 1..entry_frame_num {
-            new_frame = allocator.allocate_frame().expect("No remaining frames");
+        // The frames backing the map need not be contiguous, only the pages are
+        let flags = WRITABLE | NO_EXECUTE;
+        for i in 0..entry_frame_num {
+            let frame = allocator.allocate_frame().expect("No remaining frames");
+            let page = Page::from(COREMAP_VMA + i * PAGE_SIZE);
+            active_table.map_to(page, frame, flags, allocator);
         }
 
-        let mut frame_range = super::FrameIter::new(first_frame, new_frame);
-        assert!(frame_range.range() == entry_frame_num);
+        let entries = ::core::slice::from_raw_parts_mut(COREMAP_VMA as *mut CoreMapEntry, frame_num);
+        for entry in entries.iter_mut() {
+            *entry = CoreMapEntry::new(0, IS_ALLOCATED | IS_KERNEL_FRAME, 0);
+        }
 
-        let flags = WRITABLE;
-        for frame in frame_range.clone() {
-            active_table.higher_kernel_map(frame, flags, allocator);
+        CoreMap {
+            entries: entries,
+            size: frame_num,
         }
+    }
 
-        
-        for i in 0..frame_num {
-            let entry_addr = map_addr + KERNEL_VMA + i * 8;
-            println!("{:#x}", unsafe{*(entry_addr as *mut u64)});
+    /// Core map over entries on the host heap, for unit tests. The
+    /// frames start out allocated to the kernel, as in `new`.
+    #[cfg(test)]
+    pub fn from_entries(entries: &'static mut [CoreMapEntry]) -> CoreMap {
+        for entry in entries.iter_mut() {
+            *entry = CoreMapEntry::new(0, IS_ALLOCATED | IS_KERNEL_FRAME, 0);
         }
-        
+        CoreMap {
+            size: entries.len(),
+            entries: entries,
+        }
+    }
 
-        println!("Frame range: {:?}", frame_range);
+    // Number of frames covered by the core map
+    pub fn size(&self) -> usize {
+        self.size
+    }
 
-        unimplemented!()
+    pub fn contains(&self, frame: &Frame) -> bool {
+        frame.number < self.size
     }
 
     // Set entry in core map to be unused
     pub fn set_unused(&mut self, frame: Frame){
-        unimplemented!()
+        self[frame.number] = CoreMapEntry::new_zero();
+    }
+
+    pub fn set(&mut self, frame: Frame, flags: CoreMapFlags, pid: u8) {
+        self[frame.number] = CoreMapEntry::new(0, flags, pid);
+    }
+
+    /// Record the virtual address an allocated frame is mapped at.
+    pub fn set_mapping(&mut self, frame: &Frame, vma: VirtualAddress) {
+        assert!(self[frame.number].flags().contains(IS_ALLOCATED),
+            "Recording mapping of unallocated frame {:?}", frame);
+        self[frame.number].set_vma(vma);
     }
 
-    pub fn set(&mut self, frame: Frame, flags: CoreMapFlags, pid: u32) {
-        unimplemented!()
+    /// Hand an allocated frame over to a process.
+    pub fn set_owner(&mut self, frame: &Frame, pid: u8) {
+        let entry = &mut self[frame.number];
+        assert!(entry.flags().contains(IS_ALLOCATED),
+            "Setting owner of unallocated frame {:?}", frame);
+        let mut flags = entry.flags();
+        if pid == 0 {
+            flags.insert(IS_KERNEL_FRAME);
+        }
+        else {
+            flags.remove(IS_KERNEL_FRAME);
+        }
+        entry.0 = (entry.0 & !0xff) | (flags.bits() as u64);
+        entry.set_pid(pid);
+    }
+
+    /// Take another reference to a shared frame, returning the new count.
+    pub fn get_ref(&mut self, frame: &Frame) -> usize {
+        let entry = &mut self[frame.number];
+        assert!(entry.flags().contains(IS_ALLOCATED),
+            "Sharing unallocated frame {:?}", frame);
+        let refcount = entry.refcount() + 1;
+        entry.set_refcount(refcount);
+        refcount
+    }
+
+    /// Drop a reference to a frame, returning the references left. The
+    /// frame may only be freed once this reaches zero.
+    pub fn put_ref(&mut self, frame: &Frame) -> usize {
+        let entry = &mut self[frame.number];
+        assert!(entry.refcount() > 0, "Reference count underflow on frame {:?}", frame);
+        let refcount = entry.refcount() - 1;
+        entry.set_refcount(refcount);
+        refcount
+    }
+
+    pub fn refcount(&self, frame: &Frame) -> usize {
+        self[frame.number].refcount()
+    }
+
+    /// Process owning a frame, or `None` if the frame is free. Kernel
+    /// frames are owned by pid 0.
+    pub fn owner(&self, frame: &Frame) -> Option<u8> {
+        let entry = self[frame.number];
+        if entry.flags().contains(IS_ALLOCATED) {
+            Some(entry.pid())
+        }
+        else {
+            None
+        }
+    }
+
+    /// Virtual address recorded for a frame. Shared frames only keep
+    /// the first mapping.
+    pub fn mapping(&self, frame: &Frame) -> Option<VirtualAddress> {
+        let entry = self[frame.number];
+        if entry.flags().contains(IS_ALLOCATED) {
+            entry.vma()
+        }
+        else {
+            None
+        }
+    }
+
+    /// Number of allocated frames owned by a process.
+    pub fn frames_owned_by(&self, pid: u8) -> usize {
+        self.entries.iter()
+            .filter(|entry| entry.flags().contains(IS_ALLOCATED) && entry.pid() == pid)
+            .count()
+    }
+
+    /// Number of free frames from frame `start` up to, but excluding,
+    /// frame `end`.
+    pub fn free_frames_in(&self, start: usize, end: usize) -> usize {
+        let end = if end > self.size { self.size } else { end };
+        if start >= end {
+            return 0;
+        }
+        self.entries[start..end].iter()
+            .filter(|entry| !entry.flags().contains(IS_ALLOCATED))
+            .count()
     }
-}
\ No newline at end of file
+
+    /// Number of allocated frames owned by each pid, indexed by pid.
+    pub fn owner_counts(&self) -> [usize; 256] {
+        let mut counts = [0; 256];
+        for entry in self.entries.iter() {
+            if entry.flags().contains(IS_ALLOCATED) {
+                counts[entry.pid() as usize] += 1;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use super::*;
+
+    fn coremap(frames: usize) -> CoreMap {
+        let entries = vec![CoreMapEntry::new_zero(); frames].into_boxed_slice();
+        CoreMap::from_entries(unsafe { &mut *Box::into_raw(entries) })
+    }
+
+    #[test]
+    fn references_are_counted() {
+        let mut coremap = coremap(4);
+        let frame = Frame { number: 2 };
+        assert_eq!(coremap.refcount(&frame), 1);
+        assert_eq!(coremap.get_ref(&frame), 2);
+        assert_eq!(coremap.get_ref(&frame), 3);
+        assert_eq!(coremap.put_ref(&frame), 2);
+        assert_eq!(coremap.put_ref(&frame), 1);
+        assert_eq!(coremap.put_ref(&frame), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reference_count_underflow_panics() {
+        let mut coremap = coremap(1);
+        let frame = Frame { number: 0 };
+        coremap.put_ref(&frame);
+        coremap.put_ref(&frame);
+    }
+
+    #[test]
+    fn owner_and_mapping_survive_reference_changes() {
+        let mut coremap = coremap(4);
+        let frame = Frame { number: 1 };
+        coremap.set_owner(&frame, 7);
+        coremap.set_mapping(&frame, 0xffff_8000_0010_0000);
+        coremap.get_ref(&frame);
+        assert_eq!(coremap.owner(&frame), Some(7));
+        assert_eq!(coremap.mapping(&frame), Some(0xffff_8000_0010_0000));
+        assert_eq!(coremap.frames_owned_by(7), 1);
+        assert_eq!(coremap.frames_owned_by(0), 3);
+
+        coremap.set_owner(&frame, 0);
+        assert!(coremap[1].flags().contains(IS_KERNEL_FRAME));
+        assert_eq!(coremap.refcount(&frame), 2);
+    }
+
+    #[test]
+    fn free_frames_have_no_owner() {
+        let mut coremap = coremap(4);
+        coremap.set_unused(Frame { number: 3 });
+        assert_eq!(coremap.owner(&Frame { number: 3 }), None);
+        assert_eq!(coremap.mapping(&Frame { number: 3 }), None);
+        assert_eq!(coremap.free_frames_in(0, 8), 1);
+    }
+
+    #[test]
+    fn free_heads_link_their_list() {
+        let entry = CoreMapEntry::new_free_head(5, Some(42));
+        assert!(entry.is_free_head());
+        assert_eq!(entry.free_order(), 5);
+        assert_eq!(entry.free_next(), Some(42));
+        assert_eq!(CoreMapEntry::new_free_head(0, None).free_next(), None);
+    }
+}
@@ -5,9 +5,17 @@
 
 // External Imports
 use multiboot2::{MemoryAreaIter, MemoryArea};
+use util::without_interrupts;
 use super::*;
 
-mod coremap;
+pub mod coremap;
+pub mod reserved;
+pub mod zero_pool;
+mod buddy;
+
+pub use self::buddy::BuddyAllocator;
+pub use self::reserved::{ReservedRanges, ReservedRange, ReservedKind, ReservedError};
+pub use self::zero_pool::{ZeroPoolStats, ZERO_POOL_BATCH, refill_zero_pool, zero_pool_stats};
 
 // Public struct for Frames
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
@@ -91,6 +99,31 @@ pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn allocate_contiguous_frames(&mut self, num: usize) -> Option<FrameIter>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    /// Frame known to be filled with zeros, if the allocator keeps any
+    /// at hand. Callers fall back to `allocate_frame` and clear it.
+    fn take_zeroed_frame(&mut self) -> Option<Frame> {
+        None
+    }
+}
+
+/// Clear a frame through the physical memory map.
+pub fn zero_frame(frame: &Frame) {
+    unsafe {
+        ::core::ptr::write_bytes(phys_to_virt(frame.start_address()) as *mut u8, 0, PAGE_SIZE);
+    }
+}
+
+/// Zeroed frame from `allocator`, cleared on the spot if it has no
+/// pre-zeroed one. Needs the physical memory map.
+pub fn allocate_zeroed_frame<A>(allocator: &mut A) -> Option<Frame> where A: FrameAllocator {
+    if let Some(frame) = allocator.take_zeroed_frame() {
+        return Some(frame);
+    }
+    allocator.allocate_frame().map(|frame| {
+        zero_frame(&frame);
+        frame
+    })
 }
 
 #[derive(Debug)]
@@ -98,26 +131,18 @@ pub struct InitialFrameAllocator {
     areas: MemoryAreaIter,
     current_area: Option<&'static MemoryArea>,
     next_frame: Frame,
-    kernel_start: Frame,
-    kernel_end: Frame,
-    multiboot_start: Frame,
-    multiboot_end: Frame,
+    reserved: ReservedRanges,
     freed_frames: [Option<usize>; 8],
 }
 
 impl InitialFrameAllocator {
-    pub fn new(kernel_start:usize, kernel_end: usize,
-                multiboot_start: usize, multiboot_end: usize,
-                memory_areas: MemoryAreaIter) -> InitialFrameAllocator {
+    pub fn new(reserved: ReservedRanges, memory_areas: MemoryAreaIter) -> InitialFrameAllocator {
 
         let mut allocator = InitialFrameAllocator {
             areas: memory_areas,
             current_area: None,
             next_frame: Frame::from(0 as PhysicalAddress),
-            kernel_start: Frame::from(kernel_start as PhysicalAddress),
-            kernel_end: Frame::from(kernel_end as PhysicalAddress),
-            multiboot_start: Frame::from(multiboot_start as PhysicalAddress),
-            multiboot_end: Frame::from(multiboot_end as PhysicalAddress),
+            reserved: reserved,
             freed_frames: [None;8],
         };
         allocator.select_next_area();
@@ -147,11 +172,8 @@ impl FrameAllocator for InitialFrameAllocator {
             if frame > area_last_frame {
                 self.select_next_area();
             }
-            else if frame >= self.kernel_start && frame <= self.kernel_end {
-                self.next_frame = Frame{ number: self.kernel_end.number + 1 };
-            }
-            else if frame >= self.multiboot_start && frame <= self.multiboot_end {
-                self.next_frame = Frame{ number: self.multiboot_end.number + 1 };
+            else if let Some(range) = self.reserved.find(frame.start_address()) {
+                self.next_frame = Frame::from(range.end);
             }
             else {
                 self.next_frame.number += 1;
@@ -164,8 +186,10 @@ impl FrameAllocator for InitialFrameAllocator {
         }
     }
 
-    fn allocate_contiguous_frames(&mut self, num:usize) -> Option<FrameIter> {
-        unimplemented!() // DO NOT USE DURING INITIALIZATION
+    /// Runs of frames are left to the buddy allocator, which takes over
+    /// once the coremap is set up.
+    fn allocate_contiguous_frames(&mut self, _num: usize) -> Option<FrameIter> {
+        None
     }
 
     fn deallocate_frame(&mut self, frame: Frame){
@@ -179,49 +203,86 @@ impl FrameAllocator for InitialFrameAllocator {
     }
 }
 
-pub struct BuddyAllocator {
-    coremap: coremap::CoreMap,
-    // frame level
-    // 2x frame level
-    // 4x frame level
-    // 8x frame level
+/// System-wide frame allocator, set up by `init_mem` once the kernel
+/// has been remapped.
+static FRAME_ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+
+/// Hand the frame allocator over to the buddy allocator.
+pub fn init_frame_allocator(allocator: BuddyAllocator) {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    assert!(frame_allocator.is_none(), "Frame allocator already initialized!");
+    *frame_allocator = Some(allocator);
 }
 
-// Convert Initial Allocator to Core map allocator
+/// Handle to the system-wide buddy allocator, usable wherever a
+/// `FrameAllocator` is expected.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+impl GlobalFrameAllocator {
+    // Interrupts are held off while the allocator is locked, so that a
+    // handler allocating frames cannot spin on it forever
+    fn with<F, R>(f: F) -> R where F: FnOnce(&mut BuddyAllocator) -> R {
+        without_interrupts(|| {
+            f(FRAME_ALLOCATOR.lock().as_mut().expect("Frame allocator not initialized"))
+        })
+    }
 
-use multiboot2;
+    /// Number of frames the buddy allocator has left.
+    pub fn free_frames() -> usize {
+        GlobalFrameAllocator::with(|allocator| allocator.free_frames())
+    }
 
-impl BuddyAllocator {
-    pub fn new(init_frame_alloc: InitialFrameAllocator,
-            active_table: &mut ActivePageTable,
-            boot_info: &multiboot2::BootInformation,
-            ) -> BuddyAllocator {
-        use self::coremap::*;
-        let mut init_frame_alloc = init_frame_alloc;
-        let elf_sections_tag = boot_info
-                    .elf_sections_tag()
-                    .expect("Kernel ELF sections required.");
-        let elf_sections = elf_sections_tag.sections();
-        let mem_sections_tag = boot_info.memory_map_tag().expect("Memory map required.");
-        let mem_sections = mem_sections_tag.memory_areas();
+    /// Run `f` on the core map behind the global allocator.
+    pub fn with_coremap<F, R>(f: F) -> R where F: FnOnce(&mut coremap::CoreMap) -> R {
+        GlobalFrameAllocator::with(|allocator| f(allocator.coremap_mut()))
+    }
+}
 
-        let cm = unsafe{coremap::CoreMap::new(mem_sections,active_table,&mut init_frame_alloc)};
+/// Note in the core map that `frame` is mapped at `vma`. Frames that
+/// already have a mapping keep it. Does nothing before the buddy
+/// allocator takes over, and must not be called with it locked.
+pub fn record_mapping(frame: &Frame, vma: VirtualAddress) {
+    without_interrupts(|| {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            let coremap = allocator.coremap_mut();
+            if coremap.contains(frame) && coremap.owner(frame).is_some()
+                && coremap.mapping(frame).is_none() {
+                coremap.set_mapping(frame, vma);
+            }
+        }
+    })
+}
 
-        unimplemented!()
-    }
+/// Take another core map reference on `frame`, for a page table entry
+/// copied into a new address space. Frames outside the core map are not
+/// counted, nor is anything before the buddy allocator takes over.
+pub fn share_frame(frame: &Frame) {
+    without_interrupts(|| {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            let coremap = allocator.coremap_mut();
+            if coremap.contains(frame) {
+                coremap.get_ref(frame);
+            }
+        }
+    })
 }
 
-impl FrameAllocator for BuddyAllocator {
+impl FrameAllocator for GlobalFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
-        unimplemented!()
+        GlobalFrameAllocator::with(|allocator| allocator.allocate_frame())
+            .or_else(zero_pool::reclaim_pooled_frame)
     }
 
-    fn allocate_contiguous_frames(&mut self, num:usize) -> Option<FrameIter> {
-        unimplemented!()
+    fn allocate_contiguous_frames(&mut self, num: usize) -> Option<FrameIter> {
+        GlobalFrameAllocator::with(|allocator| allocator.allocate_contiguous_frames(num))
     }
 
-    fn deallocate_frame(&mut self, frame: Frame){
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        GlobalFrameAllocator::with(|allocator| allocator.deallocate_frame(frame))
     }
-}
 
+    fn take_zeroed_frame(&mut self) -> Option<Frame> {
+        zero_pool::take_zeroed_frame()
+    }
+}
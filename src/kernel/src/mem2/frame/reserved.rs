@@ -0,0 +1,321 @@
+/*  Reserved physical ranges
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::{ptr, slice};
+use multiboot2::BootInformation;
+use super::super::{PhysicalAddress, PAGE_SIZE, KERNEL_VMA};
+
+pub const MAX_RESERVED_RANGES: usize = 32;
+
+// Multiboot2 tag types
+const TAG_END: u32 = 0;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MEMORY_AVAILABLE: u32 = 1;
+
+// Length of the header every ACPI table starts with
+const ACPI_HEADER_SIZE: usize = 36;
+
+// Physical memory the boot page tables map, at `KERNEL_VMA` as well.
+// ACPI tables above it cannot be read this early, but they normally
+// sit in memory map entries that are reserved anyway.
+const BOOT_MAPPED_END: PhysicalAddress = 1 << 30;
+
+/// What a reserved range holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedKind {
+    KernelImage,
+    BootInformation,
+    Module,
+    /// Memory map entry that is not available RAM: reserved, ACPI
+    /// reclaimable or NVS, or defective.
+    Firmware,
+    AcpiTables,
+    Framebuffer,
+    /// Ranges of several kinds merged into one.
+    Merged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedError {
+    /// All `MAX_RESERVED_RANGES` slots are taken by disjoint ranges.
+    TooManyRanges,
+}
+
+/// Frame aligned physical range `[start, end)` that must never be
+/// handed out as free memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedRange {
+    pub start: PhysicalAddress,
+    pub end: PhysicalAddress,
+    pub kind: ReservedKind,
+}
+
+impl ReservedRange {
+    pub fn contains(&self, address: PhysicalAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+/// Sorted list of reserved ranges, with overlapping and adjacent
+/// ranges merged. Kept in a fixed array, as it is needed before there
+/// is a heap.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedRanges {
+    ranges: [Option<ReservedRange>; MAX_RESERVED_RANGES],
+    count: usize,
+}
+
+#[repr(C)]
+struct TagHeader {
+    typ: u32,
+    size: u32,
+}
+
+impl ReservedRanges {
+    pub fn new() -> ReservedRanges {
+        ReservedRanges {
+            ranges: [None; MAX_RESERVED_RANGES],
+            count: 0,
+        }
+    }
+
+    /// Ranges for the kernel image, the multiboot information and
+    /// everything its tags describe: modules, memory map entries that
+    /// are not available RAM, the ACPI tables and the framebuffer.
+    pub fn from_boot_info(boot_info: &BootInformation, kernel_start: PhysicalAddress,
+                          kernel_end: PhysicalAddress, multiboot_start: PhysicalAddress,
+                          multiboot_end: PhysicalAddress)
+                          -> Result<ReservedRanges, ReservedError> {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(kernel_start, kernel_end, ReservedKind::KernelImage)?;
+        reserved.insert(multiboot_start, multiboot_end, ReservedKind::BootInformation)?;
+
+        let mut address = boot_info.start_address() + 8;
+        while address + 8 <= boot_info.end_address() {
+            let tag = unsafe { &*(address as *const TagHeader) };
+            match tag.typ {
+                TAG_END => break,
+                TAG_MODULE => unsafe {
+                    let start = read::<u32>(address + 8) as PhysicalAddress;
+                    let end = read::<u32>(address + 12) as PhysicalAddress;
+                    reserved.insert(start, end, ReservedKind::Module)?;
+                },
+                TAG_MEMORY_MAP => unsafe {
+                    let entry_size = read::<u32>(address + 8) as usize;
+                    let mut entry = address + 16;
+                    while entry_size > 0 && entry + entry_size <= address + tag.size as usize {
+                        let base = read::<u64>(entry) as PhysicalAddress;
+                        let length = read::<u64>(entry + 8) as usize;
+                        if read::<u32>(entry + 16) != MEMORY_AVAILABLE {
+                            reserved.insert(base, base + length, ReservedKind::Firmware)?;
+                        }
+                        entry += entry_size;
+                    }
+                },
+                TAG_FRAMEBUFFER => unsafe {
+                    let base = read::<u64>(address + 8) as PhysicalAddress;
+                    let pitch = read::<u32>(address + 16) as usize;
+                    let height = read::<u32>(address + 24) as usize;
+                    reserved.insert(base, base + pitch * height, ReservedKind::Framebuffer)?;
+                },
+                // The tags hold a copy of the RSDP, which points at the root table
+                TAG_ACPI_OLD => unsafe {
+                    let rsdt = read::<u32>(address + 8 + 16) as PhysicalAddress;
+                    reserved.insert_acpi_tables(rsdt, 4)?;
+                },
+                TAG_ACPI_NEW => unsafe {
+                    let xsdt = read::<u64>(address + 8 + 24) as PhysicalAddress;
+                    reserved.insert_acpi_tables(xsdt, 8)?;
+                },
+                _ => {}
+            }
+            address += (tag.size as usize + 7) & !7;
+        }
+        Ok(reserved)
+    }
+
+    // Reserve the root table at `root` and every table it lists, whose
+    // addresses are `entry_size` bytes wide
+    unsafe fn insert_acpi_tables(&mut self, root: PhysicalAddress, entry_size: usize)
+                                 -> Result<(), ReservedError> {
+        let root_length = match self.insert_acpi_table(root)? {
+            Some(length) => length,
+            None => return Ok(()),
+        };
+        for i in 0..((root_length - ACPI_HEADER_SIZE) / entry_size) {
+            let entry = KERNEL_VMA + root + ACPI_HEADER_SIZE + i * entry_size;
+            let table = if entry_size == 8 {
+                ptr::read_unaligned(entry as *const u64) as PhysicalAddress
+            }
+            else {
+                ptr::read_unaligned(entry as *const u32) as PhysicalAddress
+            };
+            self.insert_acpi_table(table)?;
+        }
+        Ok(())
+    }
+
+    // Reserve the ACPI table at `table` as far as the length in its
+    // header goes, returning the length. Tables out of reach of the boot
+    // page tables only get their header reserved.
+    unsafe fn insert_acpi_table(&mut self, table: PhysicalAddress)
+                                -> Result<Option<usize>, ReservedError> {
+        if table == 0 {
+            return Ok(None);
+        }
+        let length = if table + ACPI_HEADER_SIZE <= BOOT_MAPPED_END {
+            ptr::read_unaligned((KERNEL_VMA + table + 4) as *const u32) as usize
+        }
+        else {
+            0
+        };
+        if length < ACPI_HEADER_SIZE || table + length > BOOT_MAPPED_END {
+            self.insert(table, table + ACPI_HEADER_SIZE, ReservedKind::AcpiTables)?;
+            return Ok(None);
+        }
+        self.insert(table, table + length, ReservedKind::AcpiTables)?;
+        Ok(Some(length))
+    }
+
+    /// Add `[start, end)`, widened to whole frames, merging it with the
+    /// ranges it overlaps or touches. The ranges are left as they were
+    /// if there is no room for it.
+    pub fn insert(&mut self, start: PhysicalAddress, end: PhysicalAddress, kind: ReservedKind)
+                  -> Result<(), ReservedError> {
+        if start >= end {
+            return Ok(());
+        }
+        let mut merged = ReservedRange {
+            start: start & !(PAGE_SIZE - 1),
+            end: (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            kind: kind,
+        };
+
+        let mut kept = [None; MAX_RESERVED_RANGES];
+        let mut count = 0;
+        let mut placed = false;
+        for range in self.iter() {
+            if range.end < merged.start {
+                push(&mut kept, &mut count, *range)?;
+            }
+            else if range.start > merged.end {
+                if !placed {
+                    push(&mut kept, &mut count, merged)?;
+                    placed = true;
+                }
+                push(&mut kept, &mut count, *range)?;
+            }
+            else {
+                if range.start < merged.start {
+                    merged.start = range.start;
+                }
+                if range.end > merged.end {
+                    merged.end = range.end;
+                }
+                if range.kind != merged.kind {
+                    merged.kind = ReservedKind::Merged;
+                }
+            }
+        }
+        if !placed {
+            push(&mut kept, &mut count, merged)?;
+        }
+        self.ranges = kept;
+        self.count = count;
+        Ok(())
+    }
+
+    /// Ranges in ascending order.
+    pub fn iter(&self) -> ReservedIter {
+        ReservedIter { ranges: self.ranges[..self.count].iter() }
+    }
+
+    /// Range holding the frame at `address`, if it is reserved.
+    pub fn find(&self, address: PhysicalAddress) -> Option<ReservedRange> {
+        self.iter().find(|range| range.contains(address)).cloned()
+    }
+}
+
+pub struct ReservedIter<'a> {
+    ranges: slice::Iter<'a, Option<ReservedRange>>,
+}
+
+impl<'a> Iterator for ReservedIter<'a> {
+    type Item = &'a ReservedRange;
+
+    fn next(&mut self) -> Option<&'a ReservedRange> {
+        self.ranges.next().map(|range| range.as_ref().unwrap())
+    }
+}
+
+fn push(ranges: &mut [Option<ReservedRange>; MAX_RESERVED_RANGES], count: &mut usize,
+        range: ReservedRange) -> Result<(), ReservedError> {
+    if *count == MAX_RESERVED_RANGES {
+        return Err(ReservedError::TooManyRanges);
+    }
+    ranges[*count] = Some(range);
+    *count += 1;
+    Ok(())
+}
+
+unsafe fn read<T>(address: usize) -> T {
+    ptr::read(address as *const T)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(reserved: &ReservedRanges) -> Vec<(PhysicalAddress, PhysicalAddress)> {
+        reserved.iter().map(|range| (range.start, range.end)).collect()
+    }
+
+    #[test]
+    fn insert_keeps_ranges_sorted_and_frame_aligned() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(0x5000, 0x6000, ReservedKind::Module).unwrap();
+        reserved.insert(0x1100, 0x1f00, ReservedKind::Firmware).unwrap();
+        reserved.insert(0x9000, 0x9000, ReservedKind::Firmware).unwrap();
+        assert_eq!(bounds(&reserved), vec![(0x1000, 0x2000), (0x5000, 0x6000)]);
+        assert_eq!(reserved.find(0x1800).unwrap().kind, ReservedKind::Firmware);
+        assert!(reserved.find(0x2000).is_none());
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent_ranges() {
+        let mut reserved = ReservedRanges::new();
+        reserved.insert(0x1000, 0x2000, ReservedKind::KernelImage).unwrap();
+        reserved.insert(0x4000, 0x5000, ReservedKind::KernelImage).unwrap();
+        reserved.insert(0x2000, 0x3000, ReservedKind::KernelImage).unwrap();
+        assert_eq!(bounds(&reserved), vec![(0x1000, 0x3000), (0x4000, 0x5000)]);
+        assert_eq!(reserved.find(0x1000).unwrap().kind, ReservedKind::KernelImage);
+
+        // Bridging the gap swallows both neighbours
+        reserved.insert(0x2800, 0x4800, ReservedKind::AcpiTables).unwrap();
+        assert_eq!(bounds(&reserved), vec![(0x1000, 0x5000)]);
+        assert_eq!(reserved.find(0x1000).unwrap().kind, ReservedKind::Merged);
+    }
+
+    #[test]
+    fn full_list_is_left_unchanged() {
+        let mut reserved = ReservedRanges::new();
+        for i in 0..MAX_RESERVED_RANGES {
+            reserved.insert(i * 0x2000, i * 0x2000 + 0x1000, ReservedKind::Firmware).unwrap();
+        }
+        let before = bounds(&reserved);
+        assert_eq!(reserved.insert(0x100_0000, 0x100_1000, ReservedKind::Module),
+                   Err(ReservedError::TooManyRanges));
+        assert_eq!(bounds(&reserved), before);
+
+        // Filling a gap merges its neighbours, which always fits
+        reserved.insert(0x1000, 0x2000, ReservedKind::Firmware).unwrap();
+        assert_eq!(reserved.iter().count(), MAX_RESERVED_RANGES - 1);
+    }
+}
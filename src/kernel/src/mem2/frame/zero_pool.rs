@@ -0,0 +1,132 @@
+/*  Pre-zeroed frame pool
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use super::*;
+
+/// Frames the pool holds when full.
+pub const ZERO_POOL_SIZE: usize = 64;
+
+/// Frames an idle CPU zeroes before checking for other work.
+pub const ZERO_POOL_BATCH: usize = 8;
+
+/// Counters of the pre-zeroed frame pool.
+#[derive(Debug, Clone, Copy)]
+pub struct ZeroPoolStats {
+    pub frames: usize,
+    pub capacity: usize,
+    /// Zeroed frames asked for and taken from the pool.
+    pub hits: usize,
+    /// Zeroed frames asked for while the pool was empty.
+    pub misses: usize,
+    /// Frames zeroed and added by refills.
+    pub refilled: usize,
+}
+
+struct ZeroPool {
+    frames: [usize; ZERO_POOL_SIZE],
+    count: usize,
+    enabled: bool,
+    hits: usize,
+    misses: usize,
+    refilled: usize,
+}
+
+impl ZeroPool {
+    fn pop(&mut self) -> Option<Frame> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(Frame { number: self.frames[self.count] })
+    }
+}
+
+static ZERO_POOL: Mutex<ZeroPool> = Mutex::new(ZeroPool {
+    frames: [0; ZERO_POOL_SIZE],
+    count: 0,
+    enabled: false,
+    hits: 0,
+    misses: 0,
+    refilled: 0,
+});
+
+/// Let the pool be refilled. Zeroing goes through the physical memory
+/// map, so this waits until it is set up.
+pub fn enable_zero_pool() {
+    ZERO_POOL.lock().enabled = true;
+}
+
+/// Take a zeroed frame from the pool. Gives up if the pool is empty, or
+/// in use by a refill that got interrupted.
+pub fn take_zeroed_frame() -> Option<Frame> {
+    let mut pool = match ZERO_POOL.try_lock() {
+        Some(pool) => pool,
+        None => return None,
+    };
+    match pool.pop() {
+        Some(frame) => {
+            pool.hits += 1;
+            Some(frame)
+        }
+        None => {
+            pool.misses += 1;
+            None
+        }
+    }
+}
+
+/// Hand back a pooled frame for any use, when the frame allocator has
+/// run dry.
+pub fn reclaim_pooled_frame() -> Option<Frame> {
+    match ZERO_POOL.try_lock() {
+        Some(mut pool) => pool.pop(),
+        None => None,
+    }
+}
+
+/// Zero free frames into the pool until it is full or `max` frames were
+/// added. Meant to run while the CPU is idle. Returns the number of
+/// frames added.
+pub fn refill_zero_pool(max: usize) -> usize {
+    let mut added = 0;
+    while added < max {
+        {
+            let pool = ZERO_POOL.lock();
+            if !pool.enabled || pool.count == ZERO_POOL_SIZE {
+                break;
+            }
+        }
+        // Straight from the buddy allocator, which is not held while zeroing
+        let frame = match GlobalFrameAllocator::with(|allocator| allocator.allocate_frame()) {
+            Some(frame) => frame,
+            None => break,
+        };
+        zero_frame(&frame);
+
+        let mut pool = ZERO_POOL.lock();
+        if pool.count == ZERO_POOL_SIZE {
+            drop(pool);
+            GlobalFrameAllocator.deallocate_frame(frame);
+            break;
+        }
+        let count = pool.count;
+        pool.frames[count] = frame.number;
+        pool.count += 1;
+        pool.refilled += 1;
+        added += 1;
+    }
+    added
+}
+
+pub fn zero_pool_stats() -> ZeroPoolStats {
+    let pool = ZERO_POOL.lock();
+    ZeroPoolStats {
+        frames: pool.count,
+        capacity: ZERO_POOL_SIZE,
+        hits: pool.hits,
+        misses: pool.misses,
+        refilled: pool.refilled,
+    }
+}
@@ -0,0 +1,111 @@
+/*  Kernel heap module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use slab_allocator;
+use super::*;
+use super::page::table::entries::{WRITABLE, NO_EXECUTE};
+
+// Heap blocks live in the physical memory map, so handing pages to the
+// heap never has to touch the page tables.
+fn alloc_block(order: usize) -> Option<VirtualAddress> {
+    let mut allocator = GlobalFrameAllocator;
+    let frame = if order == 0 {
+        allocator.allocate_frame()
+    }
+    else {
+        allocator.allocate_contiguous_frames(1 << order)
+            .map(|mut frames| frames.next().unwrap())
+    };
+    frame.map(|frame| phys_to_virt(frame.start_address()))
+}
+
+fn free_block(address: VirtualAddress, order: usize) {
+    let mut allocator = GlobalFrameAllocator;
+    let start = Frame::from((address - KERNEL_VMA) as PhysicalAddress);
+    let end = Frame{ number: start.number + (1 << order) - 1 };
+    for frame in Frame::range_inclusive(start, end) {
+        allocator.deallocate_frame(frame);
+    }
+}
+
+// The large object region is grown through the active table, which
+// may be held by the caller of the allocation: see `ACTIVE_TABLE`.
+fn map_pages(address: VirtualAddress, count: usize) -> bool {
+    let mut table_guard = match ACTIVE_TABLE.try_lock() {
+        Some(guard) => guard,
+        None => return false,
+    };
+    let active_table = match table_guard.as_mut() {
+        Some(active_table) => active_table,
+        None => return false,
+    };
+
+    let mut allocator = GlobalFrameAllocator;
+    let start_page = Page::from(address);
+    for i in 0..count {
+        match allocator.allocate_frame() {
+            Some(frame) => active_table.map_to(start_page + i, frame, WRITABLE | NO_EXECUTE, &mut allocator),
+            None => {
+                for j in 0..i {
+                    active_table.unmap(start_page + j, &mut allocator);
+                }
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Pages that cannot be unmapped now stay in the region for reuse
+fn unmap_pages(address: VirtualAddress, count: usize) -> bool {
+    let mut table_guard = match ACTIVE_TABLE.try_lock() {
+        Some(guard) => guard,
+        None => return false,
+    };
+    let active_table = match table_guard.as_mut() {
+        Some(active_table) => active_table,
+        None => return false,
+    };
+
+    let mut allocator = GlobalFrameAllocator;
+    let start_page = Page::from(address);
+    for page in range_inclusive(start_page, start_page + (count - 1)) {
+        active_table.unmap(page, &mut allocator);
+    }
+    true
+}
+
+fn out_of_memory(report: &slab_allocator::OomReport) -> ! {
+    println!("\nKernel heap exhausted allocating {} bytes (align {})", report.size, report.align);
+    println!("    Slab caches: {} pages, {} bytes in use",
+             report.slab_pages, report.slab_bytes_in_use);
+    println!("    Large objects: {} of {} mapped pages in use, limit {} KiB",
+             report.large_pages_in_use, report.large_pages_mapped, report.heap_limit / 1024);
+    println!("    Largest free block: {} bytes", report.largest_free_block);
+    panic!("out of memory");
+}
+
+/// Point the slab allocator at the frame allocator. Large objects can
+/// only be allocated once `init_large_heap` has placed their region.
+pub fn init_heap() {
+    slab_allocator::set_oom_handler(out_of_memory);
+    slab_allocator::init(slab_allocator::PageSource {
+        alloc_block: alloc_block,
+        free_block: free_block,
+        map_pages: map_pages,
+        unmap_pages: unmap_pages,
+    });
+}
+
+/// Place the large object region at the start of the window reserved
+/// for the heap.
+pub fn init_large_heap(start: VirtualAddress) {
+    slab_allocator::init_large_region(start, HEAP_DEFAULT_LIMIT);
+}
+
+pub fn set_heap_limit(bytes: usize) {
+    assert!(bytes <= HEAP_MAX_SIZE, "Heap limit {:#x} is beyond the reserved heap window", bytes);
+    slab_allocator::set_heap_limit(bytes);
+}
@@ -0,0 +1,144 @@
+/*  Memory statistics module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use slab_allocator;
+use multiboot2::BootInformation;
+use super::*;
+use super::stack::KERNEL_STACK_NAME;
+
+/// Memory areas tracked per area. Further areas still count in the totals.
+const MAX_MEMORY_AREAS: usize = 16;
+
+/// Frames of one multiboot memory area.
+#[derive(Debug, Clone, Copy)]
+pub struct AreaInfo {
+    pub start: PhysicalAddress,
+    pub end: PhysicalAddress,
+    pub total_frames: usize,
+    pub free_frames: usize,
+}
+
+/// Frame usage of the kernel. The categories may overlap: page tables
+/// built at boot also count as reserved.
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Held since boot besides the kernel image: multiboot information,
+    /// the core map and the boot page tables.
+    pub reserved_frames: usize,
+    pub kernel_image_frames: usize,
+    /// Tables of the running address space.
+    pub page_table_frames: usize,
+    pub heap_frames: usize,
+    pub stack_frames: usize,
+    pub areas: [Option<AreaInfo>; MAX_MEMORY_AREAS],
+}
+
+struct BootLayout {
+    areas: [Option<(PhysicalAddress, PhysicalAddress)>; MAX_MEMORY_AREAS],
+    total_frames: usize,
+    kernel_image_frames: usize,
+    reserved_frames: usize,
+}
+
+static BOOT_LAYOUT: Mutex<BootLayout> = Mutex::new(BootLayout {
+    areas: [None; MAX_MEMORY_AREAS],
+    total_frames: 0,
+    kernel_image_frames: 0,
+    reserved_frames: 0,
+});
+
+// Whole frames between two physical addresses
+fn frame_span(start: PhysicalAddress, end: PhysicalAddress) -> (usize, usize) {
+    ((start + PAGE_SIZE - 1) / PAGE_SIZE, end / PAGE_SIZE)
+}
+
+/// Remember the memory areas and what boot took out of them. Must be
+/// called right after the buddy allocator takes over.
+pub fn init_meminfo(boot_info: &BootInformation, kernel_start: PhysicalAddress,
+                    kernel_end: PhysicalAddress) {
+    let mut layout = BOOT_LAYOUT.lock();
+    let areas = boot_info.memory_map_tag().expect("Memory map tag required").memory_areas();
+    let mut total = 0;
+    for (i, area) in areas.enumerate() {
+        let start = area.base_addr as PhysicalAddress;
+        let end = (area.base_addr + area.length) as PhysicalAddress;
+        let (first, last) = frame_span(start, end);
+        total += last.saturating_sub(first);
+        if i < MAX_MEMORY_AREAS {
+            layout.areas[i] = Some((start, end));
+        }
+    }
+    layout.total_frames = total;
+    layout.kernel_image_frames = (kernel_end - kernel_start + PAGE_SIZE - 1) / PAGE_SIZE;
+    layout.reserved_frames = (total - GlobalFrameAllocator::free_frames())
+        .saturating_sub(layout.kernel_image_frames);
+}
+
+/// Current frame usage, from the frame allocator, the page tables, the
+/// heap and the kernel regions.
+pub fn meminfo() -> MemInfo {
+    let mut info = MemInfo {
+        total_frames: 0,
+        free_frames: GlobalFrameAllocator::free_frames(),
+        reserved_frames: 0,
+        kernel_image_frames: 0,
+        page_table_frames: 0,
+        heap_frames: slab_allocator::heap_pages(),
+        stack_frames: 0,
+        areas: [None; MAX_MEMORY_AREAS],
+    };
+
+    {
+        let layout = BOOT_LAYOUT.lock();
+        info.total_frames = layout.total_frames;
+        info.kernel_image_frames = layout.kernel_image_frames;
+        info.reserved_frames = layout.reserved_frames;
+        GlobalFrameAllocator::with_coremap(|coremap| {
+            for (slot, area) in info.areas.iter_mut().zip(layout.areas.iter()) {
+                if let Some((start, end)) = *area {
+                    let (first, last) = frame_span(start, end);
+                    *slot = Some(AreaInfo {
+                        start: start,
+                        end: end,
+                        total_frames: last.saturating_sub(first),
+                        free_frames: coremap.free_frames_in(first, last),
+                    });
+                }
+            }
+        });
+    }
+
+    info.page_table_frames = match ACTIVE_TABLE.lock().as_ref() {
+        Some(active_table) => active_table.table_frames(),
+        None => 0,
+    };
+    info.stack_frames = with_kernel_vmas(|vmas, _| {
+        vmas.regions()
+            .filter(|vma| vma.name == KERNEL_STACK_NAME)
+            .map(|vma| vma.size() / PAGE_SIZE)
+            .sum::<usize>()
+    });
+    info
+}
+
+/// Print the memory statistics for the boot log.
+pub fn print_meminfo() {
+    let info = meminfo();
+    let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+    println!("Memory: {} KiB total, {} KiB free", kib(info.total_frames), kib(info.free_frames));
+    println!("    Kernel image: {} KiB, reserved: {} KiB",
+             kib(info.kernel_image_frames), kib(info.reserved_frames));
+    println!("    Page tables: {} KiB, heap: {} KiB, stacks: {} KiB",
+             kib(info.page_table_frames), kib(info.heap_frames), kib(info.stack_frames));
+    let pool = zero_pool_stats();
+    println!("    Zeroed frame pool: {} of {} frames, {} hits, {} misses",
+             pool.frames, pool.capacity, pool.hits, pool.misses);
+    for area in info.areas.iter().filter_map(|area| area.as_ref()) {
+        println!("    Area {:#x} - {:#x}: {} KiB, {} KiB free",
+                 area.start, area.end, kib(area.total_frames), kib(area.free_frames));
+    }
+}
@@ -0,0 +1,192 @@
+/*  Memory mapped I/O module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use volatile::Volatile;
+use x86_64::registers::msr::wrmsr;
+use super::*;
+use super::vma::*;
+use super::page::table::entries::*;
+
+const IA32_PAT: u32 = 0x277;
+
+// Physical addresses are at most 52 bits wide
+const MAX_PHYSICAL_ADDRESS: usize = 1 << 52;
+
+// Memory types of PAT entries
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x06;
+
+// Set once `init_pat` has programmed the PAT
+static PAT_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How accesses to a mapping are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    /// Uncached, but writes may be buffered and merged. Meant for
+    /// framebuffers.
+    WriteCombining,
+    /// Strictly uncached, for device registers.
+    Uncached,
+}
+
+impl CacheType {
+    /// Entry bits selecting this type under the PAT set by `init_pat`.
+    /// Without a PAT, write-combining falls back to uncached.
+    pub fn entry_flags(&self) -> EntryFlags {
+        match *self {
+            CacheType::WriteBack => EntryFlags::empty(),
+            CacheType::WriteThrough => WRITE_THROUGH,
+            CacheType::WriteCombining if PAT_ENABLED.load(Ordering::SeqCst) => NO_CACHE,
+            CacheType::WriteCombining | CacheType::Uncached => NO_CACHE | WRITE_THROUGH,
+        }
+    }
+
+    pub fn from_flags(flags: EntryFlags) -> CacheType {
+        match (flags.contains(NO_CACHE), flags.contains(WRITE_THROUGH)) {
+            (false, false) => CacheType::WriteBack,
+            (false, true) => CacheType::WriteThrough,
+            (true, false) => CacheType::WriteCombining,
+            (true, true) => CacheType::Uncached,
+        }
+    }
+}
+
+// CPUID.01H:EDX bit 16
+fn cpu_has_pat() -> bool {
+    let (_eax, edx): (u32, u32);
+    unsafe {
+        asm!("cpuid" : "={eax}"(_eax), "={edx}"(edx) : "{eax}"(1) : "rbx", "rcx" : "volatile");
+    }
+    edx & (1 << 16) != 0
+}
+
+/// Program the PAT so that the four combinations of the cache bits of
+/// an entry give the four cache types. Only the entry with NO_CACHE
+/// alone differs from the power-on setting, where it is UC-. The PAT
+/// bit of entries is not used. CPUs without a PAT are left alone.
+pub fn init_pat() {
+    if !cpu_has_pat() {
+        return;
+    }
+    let pat = PAT_WRITE_BACK | PAT_WRITE_THROUGH << 8 | PAT_WRITE_COMBINING << 16
+        | PAT_UNCACHEABLE << 24;
+    unsafe {
+        wrmsr(IA32_PAT, pat | pat << 32);
+        asm!("wbinvd" :::: "volatile");
+    }
+    flush_tlb_all();
+    PAT_ENABLED.store(true, Ordering::SeqCst);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    /// Zero sized range, or one running past the end of physical memory.
+    BadRange,
+    /// No room in the kernel address space.
+    NoSpace,
+}
+
+/// Physical device memory mapped into the kernel half. Registers are
+/// reached by byte offset from the start of the physical range.
+#[derive(Debug)]
+pub struct MmioRegion {
+    base: VirtualAddress,
+    physical: PhysicalAddress,
+    size: usize,
+    cache: CacheType,
+}
+
+impl MmioRegion {
+    pub fn base(&self) -> VirtualAddress {
+        self.base
+    }
+
+    pub fn physical(&self) -> PhysicalAddress {
+        self.physical
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn cache_type(&self) -> CacheType {
+        self.cache
+    }
+
+    // Address of a `T` at `offset`, checked against the region and for alignment
+    fn address<T>(&self, offset: usize) -> VirtualAddress {
+        assert!(offset + mem::size_of::<T>() <= self.size,
+            "Register at {:#x} outside MMIO region of {:#x} bytes", offset, self.size);
+        let address = self.base + offset;
+        assert!(address % mem::align_of::<T>() == 0,
+            "Misaligned register at {:#x}", offset);
+        address
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.address::<T>(offset) as *const T) }
+    }
+
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.address::<T>(offset) as *mut T, value) }
+    }
+
+    /// Register at `offset`, for drivers that keep a reference to it.
+    pub fn register<T: Copy>(&mut self, offset: usize) -> &mut Volatile<T> {
+        unsafe { &mut *(self.address::<T>(offset) as *mut Volatile<T>) }
+    }
+}
+
+/// Map `size` bytes of device memory at `physical` into the kernel half
+/// with the given cache type. The range need not be page aligned.
+///
+/// Unsafe because nothing stops the range from covering memory in use,
+/// and mapping memory the physical memory map covers with another cache
+/// type than write back leaves its caching undefined.
+pub unsafe fn map_mmio(physical: PhysicalAddress, size: usize, cache: CacheType)
+                       -> Result<MmioRegion, MmioError> {
+    if size == 0 || physical.checked_add(size).map_or(true, |end| end > MAX_PHYSICAL_ADDRESS) {
+        return Err(MmioError::BadRange);
+    }
+    let first = Frame::from(physical);
+    let last = Frame::from(physical + size - 1);
+    let pages = last.number - first.number + 1;
+    let flags = VMA_READ | VMA_WRITE;
+
+    with_kernel_vmas(|vmas, table| {
+        let mut allocator = GlobalFrameAllocator;
+        let start = match vmas.map(None, pages * PAGE_SIZE, flags, Backing::Reserved,
+                                   Sharing::Shared, "mmio") {
+            Ok(start) => start,
+            Err(_) => return Err(MmioError::NoSpace),
+        };
+        let page_flags = flags.entry_flags() | cache.entry_flags();
+        for (i, frame) in Frame::range_inclusive(first, last).enumerate() {
+            table.map_to(Page::from(start) + i, frame, page_flags, &mut allocator);
+        }
+        Ok(MmioRegion {
+            base: start + physical % PAGE_SIZE,
+            physical: physical,
+            size: size,
+            cache: cache,
+        })
+    })
+}
+
+/// Unmap a region, leaving the device memory behind it alone.
+pub fn unmap_mmio(region: MmioRegion) {
+    let start = region.base & !(PAGE_SIZE - 1);
+    let pages = (region.base + region.size - start + PAGE_SIZE - 1) / PAGE_SIZE;
+    with_kernel_vmas(|vmas, table| {
+        vmas.unmap(start, pages * PAGE_SIZE, table, &mut GlobalFrameAllocator)
+            .expect("MMIO region without a VMA");
+    });
+}
@@ -6,24 +6,73 @@
 // Submodules
 mod frame;
 mod page;
+mod heap;
+mod fault;
+mod vma;
+mod stack;
+mod vmalloc;
+mod meminfo;
+mod swap;
+mod shm;
+mod mmio;
 
 
 // External imports
 use multiboot2::BootInformation;
 use spin::Mutex;
+use util::{flush_tlb, flush_tlb_all};
 use self::frame::*;
 use self::page::*;
 
+pub use self::page::{InactivePageTable, PageSize};
+pub use self::page::audit::{WxViolation, WxFinding};
+pub use self::page::dump::MappedRange;
+pub use self::mmio::{CacheType, MmioError, MmioRegion, map_mmio, unmap_mmio};
+pub use self::frame::{ReservedRanges, ReservedRange, ReservedKind, ReservedError,
+                      ZeroPoolStats, ZERO_POOL_BATCH, refill_zero_pool};
+pub use self::vma::{Vma, VmaManager, VmaError, VmaFlags, Backing, Sharing, AddressSpace,
+                    with_kernel_vmas, with_user_vmas,
+                    VMA_READ, VMA_WRITE, VMA_EXEC, VMA_USER, VMA_GROWS_DOWN};
+pub use self::stack::{Stack, DEFAULT_STACK_PAGES, guard_owner};
+pub use self::vmalloc::{vmalloc, vfree};
+pub use self::meminfo::{MemInfo, AreaInfo, print_meminfo};
+pub use self::swap::{SwapError, enable_swap};
+pub use self::shm::{ShmId, ShmError, create_shared, open_shared, destroy_shared,
+                    shared_size, map_shared_kernel, unmap_shared_kernel};
+pub use self::fault::{PageFault, FaultError, handle_page_fault};
+
 // Static values
 
 static MEM_INITED : Mutex<bool> = Mutex::new(false);
 
+/// Page table of the running address space. Paths that may run while
+/// it is held, the growth of the large object heap and the page fault
+/// handler, only try to lock it: a large heap allocation made with the
+/// table locked, such as inside `with_kernel_vmas`, fails as out of
+/// memory instead of deadlocking, and a fault reports `TablesLocked`.
+static ACTIVE_TABLE: Mutex<Option<ActivePageTable>> = Mutex::new(None);
+
 
 // Constant values
 
 pub const PAGE_SIZE: usize = 4096; // 4k pages
 pub const ENTRY_COUNT: usize = 512; // 512 entries / page table
 pub const KERNEL_VMA: usize = 0xffff8000_00000000; // Canonical higher half of kernel
+pub const COREMAP_VMA: usize = 0xffffc000_00000000; // Core map entries
+pub const KERNEL_DYNAMIC_START: usize = 0xffffd000_00000000; // Kernel ranges handed out by the VMA manager
+pub const KERNEL_DYNAMIC_END: usize = 0xffffe000_00000000;
+pub const USER_START: usize = 0x00000000_00400000; // Lowest address of a user address space
+pub const USER_END: usize = 0x00008000_00000000; // End of the lower canonical half
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB window reserved for the heap
+pub const HEAP_DEFAULT_LIMIT: usize = 16 * 1024 * 1024; // 16 MiB
+pub const HUGE_PAGES: bool = true; // 2 MiB pages for the kernel image and physical memory map
+
+// Start of the physical memory map. Unit tests run on the host and
+// hand out its memory as frames, so the map is the identity there.
+#[cfg(not(test))]
+const PHYSICAL_MAP_OFFSET: usize = KERNEL_VMA;
+#[cfg(test)]
+const PHYSICAL_MAP_OFFSET: usize = 0;
 
 // Type definitions
 
@@ -39,16 +88,167 @@ pub type VirtualAddress = usize;
 
 /// Abstract struct for memory management
 pub struct MemoryManager {
-    /* Frame allocator, page tables, gdt and others */
-    active_table: ActivePageTable,
-
+    /* The active table and the frame allocator live in statics so that
+     * interrupt and heap code can reach them */
+    _private: (),
 }
 
 impl MemoryManager {
-    // TODO
+    /// Change the ceiling the kernel heap may grow to.
+    pub fn set_heap_limit(&self, bytes: usize) {
+        self::heap::set_heap_limit(bytes);
+    }
+
+    /// Process owning the frame at a physical address, or `None` if
+    /// the frame is free or not tracked.
+    pub fn frame_owner(&self, address: PhysicalAddress) -> Option<u8> {
+        let frame = Frame::from(address);
+        GlobalFrameAllocator::with_coremap(|coremap| {
+            if coremap.contains(&frame) { coremap.owner(&frame) } else { None }
+        })
+    }
+
+    /// Virtual address the frame at a physical address is mapped at.
+    pub fn frame_mapping(&self, address: PhysicalAddress) -> Option<VirtualAddress> {
+        let frame = Frame::from(address);
+        GlobalFrameAllocator::with_coremap(|coremap| {
+            if coremap.contains(&frame) { coremap.mapping(&frame) } else { None }
+        })
+    }
+
+    /// Number of references held on the frame at a physical address.
+    pub fn frame_refcount(&self, address: PhysicalAddress) -> usize {
+        let frame = Frame::from(address);
+        GlobalFrameAllocator::with_coremap(|coremap| {
+            if coremap.contains(&frame) { coremap.refcount(&frame) } else { 0 }
+        })
+    }
+
+    /// Number of frames held by a process. The kernel is pid 0.
+    pub fn frames_owned_by(&self, pid: u8) -> usize {
+        GlobalFrameAllocator::with_coremap(|coremap| coremap.frames_owned_by(pid))
+    }
+
+    /// Number of frames held by every pid, indexed by pid.
+    pub fn owner_counts(&self) -> [usize; 256] {
+        GlobalFrameAllocator::with_coremap(|coremap| coremap.owner_counts())
+    }
+
+    /// Kernel stack of `pages` pages for `owner`, with a guard page
+    /// below it. Overflowing into the guard page is reported with the
+    /// owner's name.
+    pub fn alloc_stack(&self, pages: usize, owner: &'static str) -> Option<Stack> {
+        self::stack::alloc_stack(pages, owner)
+    }
+
+    pub fn free_stack(&self, stack: Stack) {
+        self::stack::free_stack(stack)
+    }
+
+    /// Fill level and hit counts of the pre-zeroed frame pool.
+    pub fn zero_pool_stats(&self) -> ZeroPoolStats {
+        zero_pool_stats()
+    }
+
+    /// Check the running address space for writable and executable
+    /// pages, kernel pages open to user mode and executable data, and
+    /// hand each run of offending pages to `report`. Returns the number
+    /// of runs found.
+    pub fn audit_wx<F>(&self, report: F) -> usize where F: FnMut(&WxFinding) {
+        let table_guard = ACTIVE_TABLE.lock();
+        let active_table = table_guard.as_ref().expect("Page tables not initialized");
+        self::page::audit::audit_wx(active_table, report)
+    }
+
+    /// Print the mappings of the running address space as ranges of
+    /// contiguous pages, limited to `[start, end]` if a range is given.
+    pub fn dump_page_tables(&self, range: Option<(VirtualAddress, VirtualAddress)>) {
+        let (start, end) = range.unwrap_or((0, usize::max_value()));
+        let table_guard = ACTIVE_TABLE.lock();
+        let active_table = table_guard.as_ref().expect("Page tables not initialized");
+        self::page::dump::print_page_tables(active_table, start, end);
+    }
+
+    /// Frame usage by category and by memory area.
+    pub fn meminfo(&self) -> MemInfo {
+        self::meminfo::meminfo()
+    }
+
+    /// Named shared memory object of `size` bytes, zero filled.
+    pub fn create_shared(&self, name: &str, size: usize) -> Result<ShmId, ShmError> {
+        self::shm::create_shared(name, size)
+    }
+
+    pub fn open_shared(&self, name: &str) -> Option<ShmId> {
+        self::shm::open_shared(name)
+    }
+
+    /// Remove the name of a shared memory object. It is freed once no
+    /// page maps it.
+    pub fn destroy_shared(&self, id: ShmId) -> Result<(), ShmError> {
+        self::shm::destroy_shared(id)
+    }
 
-    pub fn create_new_table() -> InactivePageTable {
-        unimplemented!()
+    /// Map a shared memory object at `address` in the running address
+    /// space. Clones of the address space share the mapping.
+    pub fn map_shared(&self, id: ShmId, address: VirtualAddress, flags: VmaFlags)
+                      -> Result<(), ShmError> {
+        let mut table_guard = ACTIVE_TABLE.lock();
+        let active_table = table_guard.as_mut().expect("Page tables not initialized");
+        self::shm::map_shared(id, active_table, address, flags, &mut GlobalFrameAllocator)
+    }
+
+    /// Remove a mapping from the running address space. The object is
+    /// freed with the last page mapping it in any address space.
+    pub fn unmap_shared(&self, id: ShmId, address: VirtualAddress) -> Result<(), ShmError> {
+        let mut table_guard = ACTIVE_TABLE.lock();
+        let active_table = table_guard.as_mut().expect("Page tables not initialized");
+        self::shm::unmap_shared(id, active_table, address, &mut GlobalFrameAllocator)
+    }
+
+    /// Copy-on-write duplicate of the running address space, the basis
+    /// for fork.
+    pub fn clone_address_space(&self) -> AddressSpace {
+        self::vma::clone_address_space()
+    }
+
+    /// New address space with an empty user half, sharing the kernel half.
+    pub fn create_address_space(&self) -> AddressSpace {
+        self::vma::create_address_space()
+    }
+
+    /// Make `space` the running address space, returning the old one.
+    pub fn switch_address_space(&self, space: AddressSpace) -> AddressSpace {
+        self::vma::switch_address_space(space)
+    }
+
+    /// Tear down an address space that is not running, freeing its
+    /// tables and user frames.
+    pub fn destroy_address_space(&self, space: AddressSpace) {
+        self::vma::destroy_address_space(space)
+    }
+
+    /// Create a region in the user half of the running address space.
+    /// Anonymous regions are backed page by page as they are touched.
+    pub fn mmap(&self, address: Option<VirtualAddress>, size: usize, flags: VmaFlags,
+                backing: Backing, sharing: Sharing, name: &'static str)
+                -> Result<VirtualAddress, VmaError> {
+        with_user_vmas(|vmas, _| vmas.map(address, size, flags, backing, sharing, name))
+    }
+
+    /// Remove `[address, address + size)` from the user regions of the
+    /// running address space, freeing its pages and swap slots.
+    pub fn munmap(&self, address: VirtualAddress, size: usize) -> Result<(), VmaError> {
+        with_user_vmas(|vmas, table| vmas.unmap(address, size, table, &mut GlobalFrameAllocator))
+    }
+
+    /// Change the permissions of `[address, address + size)` in the
+    /// running address space.
+    pub fn mprotect(&self, address: VirtualAddress, size: usize, flags: VmaFlags)
+                    -> Result<(), VmaError> {
+        with_user_vmas(|vmas, table| {
+            vmas.protect(address, size, flags, table, &mut GlobalFrameAllocator)
+        })
     }
 }
 
@@ -64,48 +264,95 @@ pub fn init_mem(boot_info : &BootInformation) -> MemoryManager {
         }
     }
 
-    
+    self::mmio::init_pat();
+
     let elf_sections_tag = boot_info
                     .elf_sections_tag()
                     .expect("Kernel ELF sections required.");
     let kernel_start = elf_sections_tag
                     .sections()
                     .filter(|s| s.is_allocated())
-                    .map(|s| s.addr)
+                    .map(|s| kernel_pma(s.addr as VirtualAddress))
                     .min()
                     .unwrap();
     let kernel_end = elf_sections_tag
                     .sections()
                     .filter(|s| s.is_allocated())
-                    .map(|s| s.addr + s.size)
+                    .map(|s| kernel_pma((s.addr + s.size) as VirtualAddress))
                     .max()
                     .unwrap();
-    let multiboot_start = boot_info.start_address();
-    let multiboot_end = boot_info.end_address();
+    let multiboot_start = kernel_pma(boot_info.start_address());
+    let multiboot_end = kernel_pma(boot_info.end_address());
+
+    // Handing out a reserved frame would be worse than not booting
+    let reserved = ReservedRanges::from_boot_info(boot_info, kernel_start, kernel_end,
+                                                  multiboot_start, multiboot_end)
+        .expect("Too many reserved physical ranges");
+    print_reserved_ranges(&reserved);
 
     let mut temp_frame_alloc = frame::InitialFrameAllocator::new(
-        kernel_start as usize,
-        kernel_end as usize,
-        multiboot_start as usize,
-        multiboot_end as usize,
+        reserved,
         boot_info.memory_map_tag().unwrap().memory_areas(),
     );
 
     let mut active_table = self::page::remap_kernel(boot_info, &mut temp_frame_alloc);
     
     super::log_status("Kernel remapping to higher half", Ok(()));
+
+    self::page::drop_identity_map(&mut active_table);
+
+    super::log_status("Identity mapping removal", Ok(()));
     
     let frame_alloc = BuddyAllocator::new(temp_frame_alloc, &mut active_table, boot_info);
+    frame::init_frame_allocator(frame_alloc);
+    self::meminfo::init_meminfo(boot_info, kernel_start, kernel_end);
 
     super::log_status("Buddy frame allocator initialization", Ok(()));
 
+    self::page::map_physical_memory(boot_info, &mut active_table, &mut GlobalFrameAllocator);
+    if cfg!(debug_assertions) {
+        let findings = self::page::audit::print_wx_audit(&active_table);
+        super::log_status("Page table W^X audit",
+                          if findings == 0 { Ok(()) } else { Err(findings as isize) });
+    }
+    self::frame::zero_pool::enable_zero_pool();
+    active_table.preallocate_kernel_tables(&mut GlobalFrameAllocator);
+    *ACTIVE_TABLE.lock() = Some(active_table);
+    self::heap::init_heap();
+    self::vma::init_kernel_vmas();
+    let heap_start = with_kernel_vmas(|vmas, _| {
+        vmas.map(None, HEAP_MAX_SIZE, VMA_READ | VMA_WRITE, Backing::Reserved,
+                 Sharing::Private, "kernel heap")
+    }).expect("No room for the kernel heap");
+    self::heap::init_large_heap(heap_start);
 
+    super::log_status("Kernel heap initialization", Ok(()));
 
+    MemoryManager {
+        _private: (),
+    }
+}
 
+/// Log the physical ranges kept away from the frame allocators.
+pub fn print_reserved_ranges(reserved: &ReservedRanges) {
+    println!("Reserved physical ranges:");
+    for range in reserved.iter() {
+        println!("    {:#x} - {:#x}: {:?}", range.start, range.end, range.kind);
+    }
+}
 
-    
-    // TODO: generate a memory manager and return it
-    
+/// Address of a physical location in the physical memory map.
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    (address + PHYSICAL_MAP_OFFSET) as VirtualAddress
+}
 
-    unimplemented!()
-}
\ No newline at end of file
+/// Physical address of a kernel address, which may lie either in the
+/// low bootstrap sections or in the higher half.
+fn kernel_pma(address: VirtualAddress) -> PhysicalAddress {
+    if address >= KERNEL_VMA {
+        (address - KERNEL_VMA) as PhysicalAddress
+    }
+    else {
+        address as PhysicalAddress
+    }
+}
@@ -0,0 +1,129 @@
+/*  Page table audit module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use spin::Mutex;
+use super::*;
+
+/// Executable ranges of the kernel image that are tracked.
+const MAX_TEXT_RANGES: usize = 8;
+
+/// What is wrong with a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxViolation {
+    /// Writable and executable at once.
+    WritableExecutable,
+    /// Kernel half page reachable from user mode.
+    UserKernelPage,
+    /// Executable kernel page outside the code sections of the kernel.
+    ExecutableData,
+}
+
+/// Run of pages `[start, end)` sharing a violation and the flags in
+/// effect for them, which combine the flags of every level. The
+/// accessed, dirty and huge page bits are left out.
+#[derive(Debug, Clone, Copy)]
+pub struct WxFinding {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub violation: WxViolation,
+    pub flags: EntryFlags,
+}
+
+const VIOLATIONS: [WxViolation; 3] = [
+    WxViolation::WritableExecutable,
+    WxViolation::UserKernelPage,
+    WxViolation::ExecutableData,
+];
+
+static KERNEL_TEXT: Mutex<[Option<(VirtualAddress, VirtualAddress)>; MAX_TEXT_RANGES]> =
+    Mutex::new([None; MAX_TEXT_RANGES]);
+
+/// Note an executable section of the kernel image, so that the audit
+/// accepts it as code.
+pub fn record_kernel_text(start: VirtualAddress, end: VirtualAddress) {
+    let mut text = KERNEL_TEXT.lock();
+    let slot = text.iter().position(|range| range.is_none())
+        .expect("Too many executable kernel sections");
+    text[slot] = Some((start & !(PAGE_SIZE - 1), (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)));
+}
+
+fn is_kernel_text(start: VirtualAddress, end: VirtualAddress) -> bool {
+    KERNEL_TEXT.lock().iter().any(|range| match *range {
+        Some((text_start, text_end)) => start >= text_start && end <= text_end,
+        None => false,
+    })
+}
+
+struct Audit<F> where F: FnMut(&WxFinding) {
+    // Run being built for each violation, reported once it ends
+    runs: [Option<WxFinding>; 3],
+    findings: usize,
+    report: F,
+}
+
+impl<F> Audit<F> where F: FnMut(&WxFinding) {
+    fn check(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags) {
+        let end = start + size;
+        let executable = !flags.contains(NO_EXECUTE);
+        let kernel = start >= KERNEL_VMA;
+        let found = [
+            flags.contains(WRITABLE) && executable,
+            kernel && flags.contains(USER_ACCESSIBLE),
+            kernel && executable && !is_kernel_text(start, end),
+        ];
+        for i in 0..VIOLATIONS.len() {
+            if !found[i] {
+                continue;
+            }
+            if let Some(ref mut run) = self.runs[i] {
+                if run.end == start && run.flags == flags {
+                    run.end = end;
+                    continue;
+                }
+            }
+            self.flush(i);
+            self.runs[i] = Some(WxFinding {
+                start: start,
+                end: end,
+                violation: VIOLATIONS[i],
+                flags: flags,
+            });
+        }
+    }
+
+    fn flush(&mut self, i: usize) {
+        if let Some(run) = self.runs[i].take() {
+            self.findings += 1;
+            (self.report)(&run);
+        }
+    }
+}
+
+/// Walk every mapping of `table` and hand each run of offending pages
+/// to `report`. Returns the number of runs reported.
+pub fn audit_wx<F>(table: &InnerPageTable, report: F) -> usize where F: FnMut(&WxFinding) {
+    let mut audit = Audit {
+        runs: [None; 3],
+        findings: 0,
+        report: report,
+    };
+    table.for_each_mapping(0, usize::max_value(), |address, size, _, flags| {
+        // The accessed and dirty bits would break up runs
+        audit.check(address, size, flags - ACCESSED - DIRTY - HUGE_PAGE);
+    });
+    for i in 0..VIOLATIONS.len() {
+        audit.flush(i);
+    }
+    audit.findings
+}
+
+/// Audit `table` and print what is found. Returns the number of runs
+/// of offending pages.
+pub fn print_wx_audit(table: &InnerPageTable) -> usize {
+    audit_wx(table, |finding| {
+        println!("W^X: {:?} {:#x} - {:#x} ({:?})",
+            finding.violation, finding.start, finding.end, finding.flags);
+    })
+}
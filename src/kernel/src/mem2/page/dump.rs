@@ -0,0 +1,162 @@
+/*  Page table dump module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use core::fmt;
+use super::*;
+use super::super::mmio::CacheType;
+
+/// Run of virtually and physically contiguous pages `[start, end)` with
+/// the same flags in effect.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub physical: PhysicalAddress,
+    pub flags: EntryFlags,
+    /// Size of the huge pages making up the range, if they are huge.
+    pub huge: Option<PageSize>,
+}
+
+impl MappedRange {
+    // Whether the page at `start` carries on this range
+    fn extends(&self, start: VirtualAddress, frame: &Frame, flags: EntryFlags,
+               huge: Option<PageSize>) -> bool {
+        self.end == start && self.physical + (self.end - self.start) == frame.start_address()
+            && self.flags == flags && self.huge == huge
+    }
+}
+
+// Virtual addresses are split in halves, for `ffff8000_00100000`
+fn split(address: usize) -> (usize, usize) {
+    (address >> 32, address & 0xffff_ffff)
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start_high, start_low) = split(self.start);
+        let (end_high, end_low) = split(self.end - 1);
+        write!(f, "{:08x}_{:08x}-{:08x}_{:08x} -> {:#x} R{}{}",
+            start_high, start_low, end_high, end_low, self.physical,
+            if self.flags.contains(WRITABLE) { "W" } else { "-" },
+            if self.flags.contains(NO_EXECUTE) { "-" } else { "X" })?;
+        if self.flags.contains(USER_ACCESSIBLE) {
+            write!(f, " user")?;
+        }
+        if self.flags.contains(GLOBAL) {
+            write!(f, " global")?;
+        }
+        match CacheType::from_flags(self.flags) {
+            CacheType::WriteBack => {}
+            CacheType::WriteThrough => write!(f, " write-through")?,
+            CacheType::WriteCombining => write!(f, " write-combining")?,
+            CacheType::Uncached => write!(f, " uncached")?,
+        }
+        if self.flags.contains(COPY_ON_WRITE) {
+            write!(f, " cow")?;
+        }
+        if self.flags.contains(SHARED) {
+            write!(f, " shared")?;
+        }
+        match self.huge {
+            Some(PageSize::Size2MiB) => write!(f, " [2MiB]"),
+            Some(PageSize::Size1GiB) => write!(f, " [1GiB]"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Hand the mappings of `table` touching `[start, end]` to `f`, with
+/// contiguous pages of the same flags merged into ranges. The accessed
+/// and dirty bits are left out, so that they do not break up ranges.
+/// Returns the number of ranges.
+pub fn dump_page_tables<F>(table: &InnerPageTable, start: VirtualAddress, end: VirtualAddress,
+                           mut f: F) -> usize where F: FnMut(&MappedRange) {
+    let mut current: Option<MappedRange> = None;
+    let mut ranges = 0;
+    table.for_each_mapping(start, end, |address, size, frame, flags| {
+        let flags = flags - ACCESSED - DIRTY - HUGE_PAGE;
+        let huge = match size {
+            PAGE_SIZE => None,
+            size if size == PageSize::Size2MiB.bytes() => Some(PageSize::Size2MiB),
+            _ => Some(PageSize::Size1GiB),
+        };
+        if let Some(ref mut range) = current {
+            if range.extends(address, &frame, flags, huge) {
+                range.end += size;
+                return;
+            }
+        }
+        if let Some(range) = current.take() {
+            ranges += 1;
+            f(&range);
+        }
+        current = Some(MappedRange {
+            start: address,
+            end: address + size,
+            physical: frame.start_address(),
+            flags: flags,
+            huge: huge,
+        });
+    });
+    if let Some(range) = current {
+        ranges += 1;
+        f(&range);
+    }
+    ranges
+}
+
+/// Print the mappings of `table` touching `[start, end]`, one range per
+/// line.
+pub fn print_page_tables(table: &InnerPageTable, start: VirtualAddress, end: VirtualAddress) {
+    let ranges = dump_page_tables(table, start, end, |range| println!("{}", range));
+    println!("{} mapped ranges", ranges);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(huge: Option<PageSize>) -> MappedRange {
+        MappedRange {
+            start: 0xffff_8000_0010_0000,
+            end: 0xffff_8000_0010_2000,
+            physical: 0x10_0000,
+            flags: PRESENT | WRITABLE | NO_EXECUTE,
+            huge: huge,
+        }
+    }
+
+    #[test]
+    fn next_page_extends_range() {
+        let flags = PRESENT | WRITABLE | NO_EXECUTE;
+        assert!(range(None).extends(0xffff_8000_0010_2000, &Frame { number: 0x102 }, flags, None));
+    }
+
+    #[test]
+    fn gaps_and_changes_break_range() {
+        let flags = PRESENT | WRITABLE | NO_EXECUTE;
+        let range = range(None);
+        // Virtual gap
+        assert!(!range.extends(0xffff_8000_0010_3000, &Frame { number: 0x103 }, flags, None));
+        // Physical gap
+        assert!(!range.extends(0xffff_8000_0010_2000, &Frame { number: 0x200 }, flags, None));
+        // Other permissions
+        assert!(!range.extends(0xffff_8000_0010_2000, &Frame { number: 0x102 }, PRESENT, None));
+        // Huge page after small ones
+        assert!(!range.extends(0xffff_8000_0010_2000, &Frame { number: 0x102 }, flags,
+                               Some(PageSize::Size2MiB)));
+    }
+
+    #[test]
+    fn display_shows_permissions_and_size() {
+        let mut range = range(Some(PageSize::Size2MiB));
+        assert_eq!(format!("{}", range),
+                   "ffff8000_00100000-ffff8000_00101fff -> 0x100000 RW- [2MiB]");
+        range.flags = PRESENT | USER_ACCESSIBLE | NO_CACHE | SHARED;
+        range.huge = None;
+        assert_eq!(format!("{}", range),
+                   "ffff8000_00100000-ffff8000_00101fff -> 0x100000 R-X user write-combining shared");
+    }
+}
@@ -5,6 +5,8 @@
 
 
 pub mod table;
+pub mod audit;
+pub mod dump;
 
 use multiboot2;
 use super::*;
@@ -82,6 +84,27 @@ impl Iterator for PageIter {
     }
 }
 
+/// Sizes of huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    /// Number of 4KiB frames in a huge page.
+    pub fn frames(&self) -> usize {
+        match *self {
+            PageSize::Size2MiB => ENTRY_COUNT,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.frames() * PAGE_SIZE
+    }
+}
+
 use super::frame::*;
 use self::table::*;
 use self::table::entries::*;
@@ -116,7 +139,7 @@ impl InnerPageTable{
                 .map(|frame| {frame.start_address() + virtual_address % PAGE_SIZE })
     }
 
-    fn translate_page(&self, page: Page) -> Option<Frame> {
+    pub fn translate_page(&self, page: Page) -> Option<Frame> {
         // If exists in p4
         if let Some(p3_table) = self.p4().next_table(page.p4_index()){
             // If exists in p3
@@ -131,7 +154,7 @@ impl InnerPageTable{
                     // Retrieve the p2 table entry
                     let p2_entry = &p2_table[page.p2_index()];
                     // If it points to some frame
-                    if let Some(huge_frame) = p2_entry.pointed_frame() {
+                    if let Some(huge_frame) = p2_entry.pointed_huge_frame() {
                         // and contains the huge page flag
                         if p2_entry.flags().contains(HUGE_PAGE){
                             // 2MiB Huge pages must be 2MiB aligned
@@ -146,7 +169,7 @@ impl InnerPageTable{
             // 1GiB Huge page
             else{
                 let p3_entry = &p3_table[page.p3_index()];
-                if let Some(huge_frame) = p3_entry.pointed_frame() {
+                if let Some(huge_frame) = p3_entry.pointed_huge_frame() {
                     if p3_entry.flags().contains(HUGE_PAGE) {
                         assert!(huge_frame.number %(ENTRY_COUNT * ENTRY_COUNT) == 0, "1GiB pages must be 1GiB aligned!");
                         return Some(Frame{
@@ -159,19 +182,263 @@ impl InnerPageTable{
         None
     }
 
-    pub fn map_to<A> (&mut self, page:Page, frame:Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+    /// Level 1 entry of a page mapped by a 4KiB page table.
+    pub fn entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut().next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+    }
 
-        //println!("Page starting address: {:x}", page.start_address());
-        //println!("Page : {:#x}", page.start_address());
-        //println!("Page indices: {:#x} {:#x} {:#x} {:#x}", page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index());
+    /// First page from `page` up to `end` inclusive that is mapped by a
+    /// 4KiB entry. Missing tables and huge pages are skipped whole.
+    pub fn next_mapped_page(&self, start: Page, end: Page) -> Option<Page> {
+        let p2_span = ENTRY_COUNT * ENTRY_COUNT;
+        let p3_span = ENTRY_COUNT * p2_span;
+        let mut page = start;
+        while page <= end {
+            let p3 = match self.p4().next_table(page.p4_index()) {
+                Some(p3) => p3,
+                None => {
+                    page.number += p3_span - page.number % p3_span;
+                    continue;
+                }
+            };
+            let p2 = match p3.next_table(page.p3_index()) {
+                Some(p2) => p2,
+                None => {
+                    page.number += p2_span - page.number % p2_span;
+                    continue;
+                }
+            };
+            let p1 = match p2.next_table(page.p2_index()) {
+                Some(p1) => p1,
+                None => {
+                    page.number += ENTRY_COUNT - page.p1_index();
+                    continue;
+                }
+            };
+            if p1[page.p1_index()].pointed_frame().is_some() {
+                return Some(page);
+            }
+            page.number += 1;
+        }
+        None
+    }
+
+    /// Number of frames holding the tables of this address space,
+    /// the shared kernel half included.
+    pub fn table_frames(&self) -> usize {
+        let mut frames = 1;
+        for i in 0..(ENTRY_COUNT - 1) {
+            if let Some(p3) = self.p4().next_table(i) {
+                frames += 1;
+                for j in 0..ENTRY_COUNT {
+                    if let Some(p2) = p3.next_table(j) {
+                        frames += 1;
+                        frames += (0..ENTRY_COUNT).filter(|&k| p2.next_table(k).is_some()).count();
+                    }
+                }
+            }
+        }
+        frames
+    }
+
+    /// Call `f` with the address, size, frame and flags of every page
+    /// touching `[start, end]`, huge pages included, in ascending order.
+    /// The flags combine those of all levels: writing and user access
+    /// need every level to allow them, while one level is enough to
+    /// forbid execution. The recursive slot is skipped.
+    pub fn for_each_mapping<F>(&self, start: VirtualAddress, end: VirtualAddress, mut f: F)
+                               where F: FnMut(VirtualAddress, usize, Frame, EntryFlags) {
+        let p3_span = PageSize::Size1GiB.bytes() * ENTRY_COUNT;
+        let p4 = self.p4();
+        for i in 0..(ENTRY_COUNT - 1) {
+            let p4_base = p4_slot_address(i);
+            let p3 = match p4.next_table(i) {
+                Some(p3) if spans(p4_base, p3_span, start, end) => p3,
+                _ => continue,
+            };
+            let p4_flags = combine_flags(WRITABLE | USER_ACCESSIBLE, p4[i].flags());
+            for j in 0..ENTRY_COUNT {
+                let p3_base = p4_base + j * PageSize::Size1GiB.bytes();
+                if !spans(p3_base, PageSize::Size1GiB.bytes(), start, end) {
+                    continue;
+                }
+                let p3_flags = combine_flags(p4_flags, p3[j].flags());
+                let p2 = match p3.next_table(j) {
+                    Some(p2) => p2,
+                    None => {
+                        if let Some(frame) = p3[j].pointed_huge_frame() {
+                            f(p3_base, PageSize::Size1GiB.bytes(), frame, p3_flags);
+                        }
+                        continue;
+                    }
+                };
+                for k in 0..ENTRY_COUNT {
+                    let p2_base = p3_base + k * PageSize::Size2MiB.bytes();
+                    if !spans(p2_base, PageSize::Size2MiB.bytes(), start, end) {
+                        continue;
+                    }
+                    let p2_flags = combine_flags(p3_flags, p2[k].flags());
+                    let p1 = match p2.next_table(k) {
+                        Some(p1) => p1,
+                        None => {
+                            if let Some(frame) = p2[k].pointed_huge_frame() {
+                                f(p2_base, PageSize::Size2MiB.bytes(), frame, p2_flags);
+                            }
+                            continue;
+                        }
+                    };
+                    for l in 0..ENTRY_COUNT {
+                        let address = p2_base + l * PAGE_SIZE;
+                        if !spans(address, PAGE_SIZE, start, end) {
+                            continue;
+                        }
+                        if let Some(frame) = p1[l].pointed_frame() {
+                            f(address, PAGE_SIZE, frame, combine_flags(p2_flags, p1[l].flags()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn map_to<A> (&mut self, page:Page, frame:Frame, flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
         let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
         let mut p2 = p3.next_table_create(page.p3_index(), allocator);
         let mut p1 = p2.next_table_create(page.p2_index(), allocator);
         assert!(p1[page.p1_index()].is_unused());
         use self::entries::PRESENT;
+        record_mapping(&frame, page.start_address());
         p1[page.p1_index()].set(frame, flags | PRESENT);
     }
 
+    /// Map a huge page at `page` to the frames starting at `frame`.
+    /// Both must be aligned to the page size.
+    pub fn map_to_huge<A>(&mut self, page: Page, frame: Frame, size: PageSize,
+                          flags: EntryFlags, allocator: &mut A) where A: FrameAllocator {
+        assert!(page.number % size.frames() == 0 && frame.number % size.frames() == 0,
+            "{:?} page {:?} or frame {:?} is misaligned", size, page, frame);
+        for i in 0..size.frames() {
+            record_mapping(&Frame { number: frame.number + i }, page.start_address() + i * PAGE_SIZE);
+        }
+        let huge_flags = flags | PRESENT | HUGE_PAGE;
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+        match size {
+            PageSize::Size1GiB => {
+                assert!(p3[page.p3_index()].is_unused(), "{:?} is already mapped", page);
+                p3[page.p3_index()].set(frame, huge_flags);
+            }
+            PageSize::Size2MiB => {
+                let mut p2 = p3.next_table_create(page.p3_index(), allocator);
+                assert!(p2[page.p2_index()].is_unused(), "{:?} is already mapped", page);
+                p2[page.p2_index()].set(frame, huge_flags);
+            }
+        }
+    }
+
+    /// Unmap a huge page and free the frames backing it.
+    pub fn unmap_huge<A>(&mut self, page: Page, size: PageSize, allocator: &mut A)
+                         where A: FrameAllocator {
+        assert!(page.number % size.frames() == 0, "{:?} page {:?} is misaligned", size, page);
+        let frame = {
+            let entry = self.huge_entry_mut(page, size)
+                .expect("No huge page mapped at the address");
+            let frame = entry.pointed_huge_frame().unwrap();
+            entry.set_unused();
+            frame
+        };
+        flush_tlb(page.start_address());
+        for i in 0..size.frames() {
+            allocator.deallocate_frame(Frame { number: frame.number + i });
+        }
+        self.reclaim_tables(page, allocator);
+    }
+
+    /// Size of the huge page `page` lies in, if any.
+    pub fn huge_page_size(&self, page: Page) -> Option<PageSize> {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(PageSize::Size1GiB);
+        }
+        match p3.next_table(page.p3_index()) {
+            Some(p2) if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) => Some(PageSize::Size2MiB),
+            _ => None,
+        }
+    }
+
+    // Entry of the huge page of the given size containing `page`
+    fn huge_entry_mut(&mut self, page: Page, size: PageSize) -> Option<&mut Entry> {
+        let entry = match size {
+            PageSize::Size1GiB => self.p4_mut().next_table_mut(page.p4_index())
+                .map(|p3| &mut p3[page.p3_index()]),
+            PageSize::Size2MiB => self.p4_mut().next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .map(|p2| &mut p2[page.p2_index()]),
+        };
+        entry.and_then(|entry| {
+            if entry.flags().contains(PRESENT | HUGE_PAGE) { Some(entry) } else { None }
+        })
+    }
+
+    // Whether nothing is mapped in the huge page slot of `page`
+    fn huge_slot_unused(&self, page: Page, size: PageSize) -> bool {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return true,
+        };
+        match size {
+            PageSize::Size1GiB => p3[page.p3_index()].is_unused(),
+            PageSize::Size2MiB => match p3.next_table(page.p3_index()) {
+                Some(p2) => p2[page.p2_index()].is_unused(),
+                None => p3[page.p3_index()].is_unused(),
+            },
+        }
+    }
+
+    /// Break the huge page containing `page` into pages one level
+    /// smaller, keeping every address mapped to the same frame. Returns
+    /// false if `page` is not in a huge page. The new table is filled in
+    /// through the physical memory map before it replaces the huge page.
+    pub fn split_huge_page<A>(&mut self, page: Page, allocator: &mut A) -> bool
+                              where A: FrameAllocator {
+        let size = match self.huge_page_size(page) {
+            Some(size) => size,
+            None => return false,
+        };
+        split_huge_entry(self.huge_entry_mut(page, size).unwrap(), size, allocator);
+        flush_tlb_all();
+        true
+    }
+
+    /// Map the frames from `start` to `end` at `KERNEL_VMA` plus their
+    /// address. Aligned stretches use 2MiB pages if `HUGE_PAGES` is set.
+    /// Pages that are mapped already are left alone.
+    pub fn higher_kernel_map_range<A>(&mut self, start: Frame, end: Frame, flags: EntryFlags,
+                                      allocator: &mut A) where A: FrameAllocator {
+        let huge = PageSize::Size2MiB;
+        let mut frame = start;
+        while frame <= end {
+            let page = Page::from(frame.start_address() + KERNEL_VMA);
+            if HUGE_PAGES && frame.number % huge.frames() == 0
+                && frame.number + huge.frames() - 1 <= end.number
+                && self.huge_slot_unused(page, huge) {
+                self.map_to_huge(page, frame.clone(), huge, flags, allocator);
+                frame.number += huge.frames();
+            }
+            else {
+                if self.translate_page(page).is_none() {
+                    self.map_to(page, frame.clone(), flags, allocator);
+                }
+                frame.number += 1;
+            }
+        }
+    }
+
     pub fn map<A>(&mut self, page:Page, flags: EntryFlags, allocator: &mut A) 
                                                             where A: FrameAllocator {
         let frame = allocator.allocate_frame().expect("No remaining free frames");
@@ -190,35 +457,262 @@ impl InnerPageTable{
         let page = Page {
             number: (frame.start_address() + KERNEL_VMA) / PAGE_SIZE,
         };
-
-        //println!("{:#x}", frame.start_address() + KERNEL_VMA);
-
-        //println!("Mapping {:?} to {:?}", page, frame);
         self.map_to(page,frame,flags,allocator);
     }
 
     pub fn unmap<A> (&mut self, page: Page, allocator: &mut A) where A: FrameAllocator {
+        let frame = self.unmap_page(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
 
+    /// Unmap `page` and hand back its frame without freeing it, for
+    /// mappings of memory the frame allocator does not own.
+    pub fn unmap_page<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+                         where A: FrameAllocator {
         assert!(self.translate(page.start_address()).is_some());
-        let p1 = self.p4_mut()
-                     .next_table_mut(page.p4_index())
-                     .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                     .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                     .expect("mapping code does not support huge pages");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+        // Only this page goes away, the rest of a huge page stays mapped
+        while self.split_huge_page(page, allocator) {}
+        let frame = {
+            let p1 = self.p4_mut()
+                         .next_table_mut(page.p4_index())
+                         .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                         .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                         .expect("Page to unmap has no level 1 table");
+            let frame = p1[page.p1_index()].pointed_frame().unwrap();
+            if p1[page.p1_index()].flags().contains(SHARED) {
+                super::shm::page_unmapped(&frame);
+            }
+            p1[page.p1_index()].set_unused();
+            frame
+        };
 
-        // free empty tables
+        flush_tlb(page.start_address());
+        self.reclaim_tables(page, allocator);
+        frame
+    }
 
-        if p1.is_empty() {
-            // unmap p1 and recurse for p2
+    /// Unmap every mapped page from `start` to `end` inclusive and free
+    /// the frames behind them. Missing tables are skipped whole, huge
+    /// pages inside the range are dropped at once, and tables left
+    /// empty are freed along the way.
+    pub fn unmap_range<A>(&mut self, start: Page, end: Page, allocator: &mut A)
+                          where A: FrameAllocator {
+        let mut page = start;
+        while page <= end {
+            let covered = match self.huge_page_size(page) {
+                Some(size) if page.number % size.frames() == 0
+                    && page.number + size.frames() - 1 <= end.number => {
+                    self.unmap_huge(page, size, allocator);
+                    size.frames()
+                }
+                Some(_) => {
+                    // The range ends inside this huge page
+                    self.split_huge_page(page, allocator);
+                    0
+                }
+                None => self.unmap_table_run(page, end, allocator),
+            };
+            page.number += covered;
         }
+    }
 
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
-        tlb::flush(VirtualAddress(page.start_address()));
-        allocator.deallocate_frame(frame);
+    // Unmap the pages from `page` up to `end` that share its level 1
+    // table. Returns the number of pages dealt with, which is the rest
+    // of a whole table when one is missing.
+    fn unmap_table_run<A>(&mut self, page: Page, end: Page, allocator: &mut A) -> usize
+                          where A: FrameAllocator {
+        use core::cmp;
+
+        let p2_span = ENTRY_COUNT * ENTRY_COUNT;
+        let p3_span = ENTRY_COUNT * p2_span;
+        let count = {
+            let p3 = match self.p4_mut().next_table_mut(page.p4_index()) {
+                Some(p3) => p3,
+                None => return p3_span - page.number % p3_span,
+            };
+            let p2 = match p3.next_table_mut(page.p3_index()) {
+                Some(p2) => p2,
+                None => return p2_span - page.number % p2_span,
+            };
+            let p1 = match p2.next_table_mut(page.p2_index()) {
+                Some(p1) => p1,
+                None => return ENTRY_COUNT - page.p1_index(),
+            };
+
+            let count = cmp::min(ENTRY_COUNT - page.p1_index(), end.number - page.number + 1);
+            for i in 0..count {
+                if let Some(frame) = p1[page.p1_index() + i].pointed_frame() {
+                    if p1[page.p1_index() + i].flags().contains(SHARED) {
+                        super::shm::page_unmapped(&frame);
+                    }
+                    p1[page.p1_index() + i].set_unused();
+                    flush_tlb((page + i).start_address());
+                    allocator.deallocate_frame(frame);
+                }
+                else if let Some((slot, _)) = p1[page.p1_index() + i].swap_slot() {
+                    p1[page.p1_index() + i].set_unused();
+                    super::swap::free_slot(slot);
+                }
+            }
+            count
+        };
+        self.reclaim_tables(page, allocator);
+        count
+    }
+
+    // Free the tables on the path to `page` that were left empty. P3
+    // tables of the kernel half stay, as every address space shares them.
+    fn reclaim_tables<A>(&mut self, page: Page, allocator: &mut A) where A: FrameAllocator {
+        let p1_frame = self.p4_mut()
+                           .next_table_mut(page.p4_index())
+                           .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                           .and_then(|p2| p2.remove_empty_table(page.p2_index()));
+        if let Some(frame) = p1_frame {
+            allocator.deallocate_frame(frame);
+        }
+
+        let p2_frame = self.p4_mut()
+                           .next_table_mut(page.p4_index())
+                           .and_then(|p3| p3.remove_empty_table(page.p3_index()));
+        if let Some(frame) = p2_frame {
+            allocator.deallocate_frame(frame);
+        }
+
+        if page.p4_index() < USER_P4_ENTRIES {
+            if let Some(frame) = self.p4_mut().remove_empty_table(page.p4_index()) {
+                allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+/// P4 entries below `KERNEL_VMA`, which belong to the user half.
+const USER_P4_ENTRIES: usize = ENTRY_COUNT / 2;
+
+// Table stored in a frame, reached through the physical memory map
+unsafe fn table_at<L>(frame: &Frame) -> &'static mut PageTable<L> where L: TableLevel {
+    &mut *(phys_to_virt(frame.start_address()) as *mut PageTable<L>)
+}
+
+// Fresh zeroed table for the copy of a user address space
+fn new_table<L, A>(allocator: &mut A) -> (Frame, &'static mut PageTable<L>)
+                   where L: TableLevel, A: FrameAllocator {
+    let frame = allocate_zeroed_frame(allocator).expect("No frames available");
+    let table = unsafe { table_at::<L>(&frame) };
+    (frame, table)
+}
+
+// Replace a present huge page entry of `size` with a table of pages
+// one level smaller, mapping the same frames with the same flags. The
+// table is filled in through the physical memory map. The caller
+// flushes the TLB.
+fn split_huge_entry<A>(entry: &mut Entry, size: PageSize, allocator: &mut A)
+                       where A: FrameAllocator {
+    let table_frame = allocator.allocate_frame().expect("No frames available");
+    let huge_frame = entry.pointed_huge_frame().unwrap();
+    let flags = entry.flags();
+    let pat = entry.huge_pat();
+    // 1GiB pages become 2MiB pages, 2MiB pages become 4KiB pages
+    match size {
+        PageSize::Size1GiB => {
+            let p2 = unsafe { table_at::<Level2>(&table_frame) };
+            for i in 0..ENTRY_COUNT {
+                let frame = Frame { number: huge_frame.number + i * ENTRY_COUNT };
+                p2[i].set_huge(frame, flags, pat);
+            }
+        }
+        PageSize::Size2MiB => {
+            // Bit 7 is the PAT bit of a 4KiB entry
+            let child_flags = if pat { flags } else { flags - HUGE_PAGE };
+            let p1 = unsafe { table_at::<Level1>(&table_frame) };
+            for i in 0..ENTRY_COUNT {
+                p1[i].set(Frame { number: huge_frame.number + i }, child_flags);
+            }
+        }
     }
+    entry.set(table_frame, PRESENT | WRITABLE | (flags & USER_ACCESSIBLE));
+}
+
+// Huge pages are split first, so that every page can be made
+// copy-on-write on its own
+fn clone_p3<A>(p3: &mut PageTable<Level3>, new_p3: &mut PageTable<Level3>, allocator: &mut A)
+               where A: FrameAllocator {
+    for i in 0..ENTRY_COUNT {
+        if p3[i].flags().contains(PRESENT | HUGE_PAGE) {
+            split_huge_entry(&mut p3[i], PageSize::Size1GiB, allocator);
+        }
+        let flags = p3[i].flags();
+        if let Some(p2) = p3.next_table_mut(i) {
+            let (frame, new_p2) = new_table::<Level2, A>(allocator);
+            clone_p2(p2, new_p2, allocator);
+            new_p3[i].set(frame, flags);
+        }
+    }
+}
+
+fn clone_p2<A>(p2: &mut PageTable<Level2>, new_p2: &mut PageTable<Level2>, allocator: &mut A)
+               where A: FrameAllocator {
+    for i in 0..ENTRY_COUNT {
+        if p2[i].flags().contains(PRESENT | HUGE_PAGE) {
+            split_huge_entry(&mut p2[i], PageSize::Size2MiB, allocator);
+        }
+        let flags = p2[i].flags();
+        if let Some(p1) = p2.next_table_mut(i) {
+            let (frame, new_p1) = new_table::<Level1, A>(allocator);
+            share_p1(p1, new_p1);
+            new_p2[i].set(frame, flags);
+        }
+    }
+}
+
+// Share every mapped frame, turning writable pages copy-on-write in both
+// tables. Swapped out pages share their swap slot instead.
+fn share_p1(p1: &mut PageTable<Level1>, new_p1: &mut PageTable<Level1>) {
+    for i in 0..ENTRY_COUNT {
+        if let Some(frame) = p1[i].pointed_frame() {
+            let mut flags = p1[i].flags();
+            // Shared memory stays writable in both, as writes must be seen by both
+            if flags.contains(WRITABLE) && !flags.contains(SHARED) {
+                flags.remove(WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                p1[i].set(frame.clone(), flags);
+            }
+            if flags.contains(SHARED) {
+                super::shm::page_mapped(&frame);
+            }
+            share_frame(&frame);
+            new_p1[i].set(frame, flags);
+        }
+        else if let Some((slot, flags)) = p1[i].swap_slot() {
+            super::swap::dup_slot(slot);
+            new_p1[i].set_swapped(slot, flags);
+        }
+    }
+}
+
+// Sign extended address of a level 4 slot
+fn p4_slot_address(index: usize) -> VirtualAddress {
+    let address = index << 39;
+    if index >= ENTRY_COUNT / 2 { address | 0xffff_0000_0000_0000 } else { address }
+}
+
+// Whether `[base, base + size)` touches `[start, end]`
+fn spans(base: VirtualAddress, size: usize, start: VirtualAddress, end: VirtualAddress) -> bool {
+    base <= end && base + (size - 1) >= start
+}
+
+fn combine_flags(parent: EntryFlags, entry: EntryFlags) -> EntryFlags {
+    let mut flags = entry;
+    if !parent.contains(WRITABLE) {
+        flags.remove(WRITABLE);
+    }
+    if !parent.contains(USER_ACCESSIBLE) {
+        flags.remove(USER_ACCESSIBLE);
+    }
+    if parent.contains(NO_EXECUTE) {
+        flags.insert(NO_EXECUTE);
+    }
+    flags
 }
 
 impl Deref for ActivePageTable {
@@ -245,21 +739,71 @@ impl ActivePageTable {
     pub fn with<F> (&mut self, table: &mut InactivePageTable, 
                     temporary_page: &mut TemporaryPage, f: F) 
                     where F: FnOnce(&mut InnerPageTable){
-        use x86_64::instructions::tlb;
         use x86_64::registers::control_regs;
 
         {
             let backup = Frame::from(control_regs::cr3().0 as PhysicalAddress);
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
             self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT|WRITABLE);
-            tlb::flush_all();
+            flush_tlb_all();
             f(self);
             p4_table[511].set(backup, PRESENT | WRITABLE);
-            tlb::flush_all();
+            flush_tlb_all();
         }
         temporary_page.unmap(self);
     }
 
+    /// Duplicate the running address space. The kernel half is shared
+    /// as is. User frames are shared read-only, and writable ones are
+    /// marked copy-on-write in both tables so that the first write
+    /// gives the writer its own copy. Huge user pages are split into
+    /// 4KiB pages first. Needs the physical memory map.
+    pub fn clone_table<A>(&mut self, allocator: &mut A) -> InactivePageTable
+                          where A: FrameAllocator {
+        let (p4_frame, new_p4) = new_table::<Level4, A>(allocator);
+        for i in 0..USER_P4_ENTRIES {
+            let flags = self.p4()[i].flags();
+            if let Some(p3) = self.p4_mut().next_table_mut(i) {
+                let (frame, new_p3) = new_table::<Level3, A>(allocator);
+                clone_p3(p3, new_p3, allocator);
+                new_p4[i].set(frame, flags);
+            }
+        }
+        self.share_kernel_half(new_p4, &p4_frame);
+
+        // Pages that just lost their write permission
+        flush_tlb_all();
+        InactivePageTable { p4_frame: p4_frame }
+    }
+
+    /// New address space with an empty user half. The kernel half is
+    /// shared with the running one. Needs the physical memory map.
+    pub fn create_table<A>(&mut self, allocator: &mut A) -> InactivePageTable
+                           where A: FrameAllocator {
+        let (p4_frame, new_p4) = new_table::<Level4, A>(allocator);
+        self.share_kernel_half(new_p4, &p4_frame);
+        InactivePageTable { p4_frame: p4_frame }
+    }
+
+    /// Give every kernel P4 entry a P3 table. Tables created afterwards
+    /// share these, so kernel mappings made later show up everywhere.
+    pub fn preallocate_kernel_tables<A>(&mut self, allocator: &mut A) where A: FrameAllocator {
+        for i in USER_P4_ENTRIES..(ENTRY_COUNT - 1) {
+            self.p4_mut().next_table_create(i, allocator);
+        }
+    }
+
+    // Point the kernel entries of a new P4 at the running kernel tables,
+    // and its last entry at itself
+    fn share_kernel_half(&self, new_p4: &mut PageTable<Level4>, p4_frame: &Frame) {
+        for i in USER_P4_ENTRIES..(ENTRY_COUNT - 1) {
+            if let Some(frame) = self.p4()[i].pointed_frame() {
+                new_p4[i].set(frame, self.p4()[i].flags());
+            }
+        }
+        new_p4[ENTRY_COUNT - 1].set(p4_frame.clone(), PRESENT | WRITABLE);
+    }
+
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
         use x86_64;
         use x86_64::registers::control_regs;
@@ -294,6 +838,78 @@ impl InactivePageTable {
     }
 }
 
+impl InactivePageTable {
+    /// Free the tables of this address space and every frame mapped in
+    /// its user half. The shared kernel half stays. Must not be called
+    /// on the running table, and needs the physical memory map.
+    pub fn destroy<A>(self, allocator: &mut A) where A: FrameAllocator {
+        use x86_64::registers::control_regs;
+
+        assert!(control_regs::cr3().0 as PhysicalAddress != self.p4_frame.start_address(),
+            "Destroying the active page table");
+        {
+            let p4 = unsafe { table_at::<Level4>(&self.p4_frame) };
+            for i in 0..USER_P4_ENTRIES {
+                if let Some(frame) = p4[i].pointed_frame() {
+                    free_p3(unsafe { table_at::<Level3>(&frame) }, allocator);
+                    allocator.deallocate_frame(frame);
+                }
+            }
+        }
+        allocator.deallocate_frame(self.p4_frame);
+    }
+}
+
+fn free_p3<A>(p3: &mut PageTable<Level3>, allocator: &mut A) where A: FrameAllocator {
+    for i in 0..ENTRY_COUNT {
+        if let Some(frame) = p3[i].pointed_frame() {
+            if p3[i].flags().contains(HUGE_PAGE) {
+                let huge_frame = p3[i].pointed_huge_frame().unwrap();
+                free_huge_frames(huge_frame, PageSize::Size1GiB, allocator);
+            }
+            else {
+                free_p2(unsafe { table_at::<Level2>(&frame) }, allocator);
+                allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+fn free_p2<A>(p2: &mut PageTable<Level2>, allocator: &mut A) where A: FrameAllocator {
+    for i in 0..ENTRY_COUNT {
+        if let Some(frame) = p2[i].pointed_frame() {
+            if p2[i].flags().contains(HUGE_PAGE) {
+                let huge_frame = p2[i].pointed_huge_frame().unwrap();
+                free_huge_frames(huge_frame, PageSize::Size2MiB, allocator);
+            }
+            else {
+                free_p1(unsafe { table_at::<Level1>(&frame) }, allocator);
+                allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+fn free_p1<A>(p1: &mut PageTable<Level1>, allocator: &mut A) where A: FrameAllocator {
+    for i in 0..ENTRY_COUNT {
+        if let Some(frame) = p1[i].pointed_frame() {
+            if p1[i].flags().contains(SHARED) {
+                super::shm::page_unmapped(&frame);
+            }
+            allocator.deallocate_frame(frame);
+        }
+        else if let Some((slot, _)) = p1[i].swap_slot() {
+            super::swap::free_slot(slot);
+        }
+    }
+}
+
+fn free_huge_frames<A>(frame: Frame, size: PageSize, allocator: &mut A) where A: FrameAllocator {
+    for i in 0..size.frames() {
+        allocator.deallocate_frame(Frame { number: frame.number + i });
+    }
+}
+
 #[derive(Debug)]
 struct TemporaryPage {
     page: Page,
@@ -311,12 +927,14 @@ impl TemporaryPage {
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddress {
         use self::entries::WRITABLE;
         assert!(active_table.translate_page(self.page).is_none(), "temp page already mapped.");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
+        active_table.map_to(self.page, frame, WRITABLE | NO_EXECUTE, &mut self.allocator);
         self.page.start_address()
     }
 
+    /// Unmap the page. The frame stays with the caller, only the tables
+    /// built for the page go back to the tiny allocator.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap_page(self.page, &mut self.allocator);
     }
 
     pub fn map_table_frame(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> &mut PageTable<Level1> {
@@ -358,8 +976,8 @@ impl FrameAllocator for TinyAllocator{
         None
     }
 
-    fn allocate_contiguous_frames(&mut self, num: usize) -> Option<FrameIter> {
-        unimplemented!() // And will not implement
+    fn allocate_contiguous_frames(&mut self, _num: usize) -> Option<FrameIter> {
+        None
     }
 
     fn deallocate_frame(&mut self, frame: Frame) {
@@ -391,8 +1009,6 @@ pub fn remap_kernel<FA>(boot_info: &multiboot2::BootInformation,
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
         for section in elf_sections_tag.sections() {
-            use self::entries::WRITABLE;
-
             if !section.is_allocated() {
                 continue;
             }
@@ -417,30 +1033,21 @@ pub fn remap_kernel<FA>(boot_info: &multiboot2::BootInformation,
                 }
             };
 
-            //println!("Section range: {:#x} - {:#x}", section_start_pma, section_end_pma);
-
             assert!(section_start_pma % PAGE_SIZE == 0,
                 "sections need to be page aligned");
 
-            //println!("    mapping section at addr: {:#x}, size: {:#x}",
-            //    section_start_pma, section.size);
-
             let flags = EntryFlags::from_elf(section);
+            if !flags.contains(NO_EXECUTE) {
+                self::audit::record_kernel_text(section_start_pma + KERNEL_VMA,
+                                                section_end_pma + 1 + KERNEL_VMA);
+            }
             let start_frame = Frame::from(section_start_pma);
             let end_frame = Frame::from(section_end_pma);
-
-            //println!("Section frames: {:?} {:?}", start_frame, end_frame);
-
-            
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                //println!("Frame to map: {:?}", frame);
-                innerpt.higher_kernel_map(frame, flags, allocator);
-            }
-            
+            innerpt.higher_kernel_map_range(start_frame, end_frame, flags, allocator);
         }
 
         let vga_buffer_frame = Frame::from(0xb8000 as PhysicalAddress); 
-        innerpt.higher_kernel_map(vga_buffer_frame, WRITABLE, allocator);
+        innerpt.higher_kernel_map(vga_buffer_frame, WRITABLE | NO_EXECUTE, allocator);
 
         let multiboot_start = Frame::from({
             let mb_vma = boot_info.start_address() as VirtualAddress;
@@ -461,7 +1068,7 @@ pub fn remap_kernel<FA>(boot_info: &multiboot2::BootInformation,
             }
         });
         for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            innerpt.higher_kernel_map(frame, PRESENT, allocator);
+            innerpt.higher_kernel_map(frame, NO_EXECUTE, allocator);
         }
     });
     
@@ -470,10 +1077,210 @@ pub fn remap_kernel<FA>(boot_info: &multiboot2::BootInformation,
     let old_p4_page = Page::from(
         (old_table.p4_frame.start_address() + KERNEL_VMA) as VirtualAddress
     );
-    active_table.unmap(old_p4_page, allocator);
+    // The boot P4 table was never allocated, so only its mapping goes
+    active_table.unmap_page(old_p4_page, allocator);
     temporary_page.free(allocator);
 
-    println!("Finished kernel remapping!");
-
     active_table
 }
+
+/// Drop whatever is left of the boot identity mapping, so that null and
+/// other low addresses fault instead of reaching physical memory. The
+/// boot tables live in the kernel image and are not freed. The boot
+/// GDT is moved to its higher half alias, as it would otherwise only be
+/// reachable through the identity mapping.
+pub fn drop_identity_map(active_table: &mut ActivePageTable) {
+    use x86_64::instructions::tables::{DescriptorTablePointer, lgdt};
+
+    for i in 0..USER_P4_ENTRIES {
+        active_table.p4_mut()[i].set_unused();
+    }
+    flush_tlb_all();
+
+    // Null, the VGA buffer and the load address of the kernel were all
+    // reachable through the boot tables
+    for &address in [0, 0xb8000, 0x100000].iter() {
+        assert!(active_table.translate(address).is_none(),
+            "Boot identity mapping of {:#x} survived", address);
+    }
+
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("sgdt ($0)" :: "r"(&mut gdtr) : "memory");
+        if (gdtr.base as usize) < KERNEL_VMA {
+            gdtr.base += KERNEL_VMA as u64;
+            lgdt(&gdtr);
+        }
+    }
+}
+
+/// Map every usable frame at `KERNEL_VMA` plus its physical address, so
+/// that any frame can be reached without mapping it first. Frames that
+/// `remap_kernel` already mapped there keep their flags, and the rest
+/// of their 2MiB stretch falls back to 4KiB pages.
+pub fn map_physical_memory<A>(boot_info: &multiboot2::BootInformation,
+                              active_table: &mut ActivePageTable,
+                              allocator: &mut A)
+                              where A: FrameAllocator {
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("Memory map tag required");
+    for area in memory_map_tag.memory_areas() {
+        let start_frame = Frame::from(area.base_addr as PhysicalAddress);
+        let end_frame = Frame::from((area.base_addr + area.length - 1) as PhysicalAddress);
+        active_table.higher_kernel_map_range(start_frame, end_frame, WRITABLE | NO_EXECUTE, allocator);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::*;
+
+    /// Hands out host pages as frames, which the identity physical map of
+    /// unit tests reaches at their own address, and keeps the numbers of
+    /// the frames that come back.
+    pub struct HostFrames {
+        pub freed: Vec<usize>,
+    }
+
+    impl FrameAllocator for HostFrames {
+        fn allocate_frame(&mut self) -> Option<Frame> {
+            let memory = vec![0u8; 2 * PAGE_SIZE].into_boxed_slice();
+            let address = Box::into_raw(memory) as *mut u8 as usize;
+            Some(Frame::from((address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)))
+        }
+
+        fn allocate_contiguous_frames(&mut self, _num: usize) -> Option<FrameIter> {
+            None
+        }
+
+        fn deallocate_frame(&mut self, frame: Frame) {
+            self.freed.push(frame.number);
+        }
+    }
+
+    /// Empty address space with its P4 table in a host page.
+    pub fn host_table(frames: &mut HostFrames) -> ActivePageTable {
+        let p4_frame = frames.allocate_frame().unwrap();
+        ActivePageTable { inner: table_in(&p4_frame) }
+    }
+
+    // Address space with its P4 table in `p4_frame`
+    fn table_in(p4_frame: &Frame) -> InnerPageTable {
+        InnerPageTable {
+            p4: Unique::new(unsafe { table_at::<Level4>(p4_frame) }),
+        }
+    }
+
+    fn sorted(mut numbers: Vec<usize>) -> Vec<usize> {
+        numbers.sort();
+        numbers
+    }
+
+    #[test]
+    fn temporary_page_gives_back_tables_but_not_its_frame() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let mut table = host_table(&mut frames);
+        let mut temporary_page = TemporaryPage::new(Page { number: 0xdeadbeaf }, &mut frames);
+        let tables = sorted(temporary_page.allocator.0.iter()
+                                .map(|frame| frame.as_ref().unwrap().number).collect());
+        let frame = frames.allocate_frame().unwrap();
+
+        temporary_page.map(frame.clone(), &mut table);
+        assert_eq!(table.translate_page(temporary_page.page), Some(frame.clone()));
+        assert_eq!(table.table_frames(), 4);
+        temporary_page.unmap(&mut table);
+        assert_eq!(table.translate_page(temporary_page.page), None);
+        assert_eq!(table.table_frames(), 1);
+        assert!(frames.freed.is_empty());
+
+        temporary_page.free(&mut frames);
+        assert_eq!(sorted(frames.freed), tables);
+    }
+
+    #[test]
+    fn unmap_range_frees_frames_and_empty_tables() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let mut table = host_table(&mut frames);
+        // Two level 1 tables under one level 2 table
+        let start = Page::from(0x40_0000 - 2 * PAGE_SIZE);
+        let mut mapped = Vec::new();
+        for i in 0..4 {
+            table.map(start + i, WRITABLE, &mut frames);
+            mapped.push(table.translate_page(start + i).unwrap().number);
+        }
+        let mut expected = mapped.clone();
+        {
+            let p3 = table.p4().next_table(start.p4_index()).unwrap();
+            let p2 = p3.next_table(start.p3_index()).unwrap();
+            expected.push(table.p4()[start.p4_index()].pointed_frame().unwrap().number);
+            expected.push(p3[start.p3_index()].pointed_frame().unwrap().number);
+            expected.push(p2[start.p2_index()].pointed_frame().unwrap().number);
+            expected.push(p2[start.p2_index() + 1].pointed_frame().unwrap().number);
+        }
+        assert_eq!(table.table_frames(), 5);
+
+        // Leaves the last page, and so its tables, in place
+        table.unmap_range(start, start + 2, &mut frames);
+        assert_eq!(table.translate_page(start + 3).map(|frame| frame.number), Some(mapped[3]));
+        assert_eq!(table.table_frames(), 4);
+        table.unmap_range(start + 3, start + 3, &mut frames);
+        assert_eq!(table.table_frames(), 1);
+        assert_eq!(sorted(frames.freed), sorted(expected));
+    }
+
+    #[test]
+    fn unmap_page_keeps_frame_and_tables_in_use() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let mut table = host_table(&mut frames);
+        let page = Page::from(0x1000_0000);
+        table.map(page, WRITABLE, &mut frames);
+        table.map(page + 1, WRITABLE, &mut frames);
+        let frame = table.translate_page(page).unwrap();
+
+        assert_eq!(table.unmap_page(page, &mut frames), frame);
+        assert!(frames.freed.is_empty());
+        assert!(table.translate_page(page + 1).is_some());
+        assert_eq!(table.table_frames(), 4);
+    }
+
+    #[test]
+    fn share_p1_makes_private_writable_pages_copy_on_write() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let (_, p1) = new_table::<Level1, _>(&mut frames);
+        let (_, new_p1) = new_table::<Level1, _>(&mut frames);
+        p1[0].set(Frame { number: 0x100 }, PRESENT | WRITABLE | USER_ACCESSIBLE);
+        p1[1].set(Frame { number: 0x101 }, PRESENT | USER_ACCESSIBLE);
+        p1[2].set(Frame { number: 0x102 }, PRESENT | WRITABLE | SHARED);
+
+        share_p1(p1, new_p1);
+        assert_eq!(p1[0].flags(), PRESENT | USER_ACCESSIBLE | COPY_ON_WRITE);
+        assert_eq!(p1[1].flags(), PRESENT | USER_ACCESSIBLE);
+        assert_eq!(p1[2].flags(), PRESENT | WRITABLE | SHARED);
+        for i in 0..3 {
+            assert_eq!(new_p1[i].pointed_frame(), p1[i].pointed_frame());
+            assert_eq!(new_p1[i].flags(), p1[i].flags());
+        }
+        assert!(new_p1[3].is_unused());
+    }
+
+    #[test]
+    fn clone_table_copies_user_tables() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let mut table = host_table(&mut frames);
+        let page = Page::from(0x40_0000);
+        table.map(page, WRITABLE | USER_ACCESSIBLE, &mut frames);
+        let frame = table.translate_page(page).unwrap();
+
+        let mut clone = table_in(&table.clone_table(&mut frames).p4_frame);
+        assert_eq!(clone.translate_page(page), Some(frame));
+        assert_eq!(clone.table_frames(), 4);
+        assert!(table.p4()[page.p4_index()].pointed_frame()
+                != clone.p4()[page.p4_index()].pointed_frame());
+        for flags in vec![table.entry_mut(page).unwrap().flags(),
+                          clone.entry_mut(page).unwrap().flags()] {
+            assert!(flags.contains(COPY_ON_WRITE) && !flags.contains(WRITABLE));
+        }
+    }
+}
@@ -9,6 +9,10 @@ use super::super::frame::Frame;
 
 pub struct Entry(u64);
 
+// Bit 12 of a huge page entry selects the PAT entry, which bit 7 does
+// in a 4KiB entry. It is not part of the frame address.
+const HUGE_PAT: u64 = 1 << 12;
+
 bitflags! {
     flags EntryFlags: u64 {
         const PRESENT =         1 << 0,
@@ -20,6 +24,12 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // Bits 9 - 11 are ignored by the MMU and free for the kernel
+        const COPY_ON_WRITE =   1 << 9,
+        // Only in non-present entries, whose frame bits hold a swap slot
+        const SWAPPED =         1 << 10,
+        // Mapping of a shared memory object, kept shared when cloned
+        const SHARED =          1 << 11,
         const NO_EXECUTE =      1 << 63,
     }
 }
@@ -61,6 +71,38 @@ impl Entry {
             None
         }
     }
+    /// Frame of a huge page entry, leaving out its PAT bit.
+    pub fn pointed_huge_frame(&self) -> Option<Frame> {
+        self.pointed_frame().map(|frame| {
+            Frame::from(frame.start_address() & !(HUGE_PAT as PhysicalAddress))
+        })
+    }
+    /// Whether the PAT bit of a huge page entry is set.
+    pub fn huge_pat(&self) -> bool {
+        self.0 & HUGE_PAT != 0
+    }
+    /// Point a huge page entry at `frame`, with the PAT bit in bit 12.
+    pub fn set_huge(&mut self, frame: Frame, flags: EntryFlags, pat: bool) {
+        self.set(frame, flags | HUGE_PAGE);
+        if pat {
+            self.0 |= HUGE_PAT;
+        }
+    }
+    /// Swap slot of a page that was written out, with the flags it is
+    /// to be mapped with again.
+    pub fn swap_slot(&self) -> Option<(usize, EntryFlags)> {
+        let flags = self.flags();
+        if !flags.contains(PRESENT) && flags.contains(SWAPPED) {
+            Some(((self.0 as usize & 0x000fffff_fffff000) >> 12, flags - SWAPPED))
+        }
+        else {
+            None
+        }
+    }
+    pub fn set_swapped(&mut self, slot: usize, flags: EntryFlags) {
+        assert!(slot < 1 << 40, "Swap slot {} out of range", slot);
+        self.0 = (slot << 12) as u64 | ((flags - PRESENT) | SWAPPED).bits();
+    }
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert_eq!(frame.start_address() & 0xfff00000_00000fff, 0, "Invalid frame starting address {:x}!", frame.start_address());
         self.0 = frame.start_address() as u64 | flags.bits();
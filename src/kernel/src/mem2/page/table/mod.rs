@@ -75,14 +75,23 @@ impl <L> PageTable<L> where L: TableLevel{
     }
 }
 
-use super::super::frame::FrameAllocator;
+use super::super::frame::{Frame, FrameAllocator};
+use util::flush_tlb;
 impl<L> PageTable<L> where L: HierarchicalTableLevel {
+    // Tables reached through the recursive slot lead to the next one
+    // the same way. Any other table is reached through the physical
+    // memory map, and so is the table it points to.
     fn next_table_address(&self, index: usize) -> Option<usize> {
         use self::entries::*;
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            if table_address >= RECURSIVE_BASE {
+                Some((table_address << 9) | (index << 12))
+            }
+            else {
+                self[index].pointed_frame().map(|frame| phys_to_virt(frame.start_address()))
+            }
         }
         else{
             None
@@ -103,19 +112,39 @@ impl<L> PageTable<L> where L: HierarchicalTableLevel {
             .map(|address| unsafe{ &mut *(address as *mut _)})
     }
 
+    /// Unlink the table at `index` if it has no entries left, and hand
+    /// back its frame for the caller to free.
+    pub fn remove_empty_table(&mut self, index: usize) -> Option<Frame> {
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return None,
+        };
+        let frame = self[index].pointed_frame().unwrap();
+        self[index].set_unused();
+        flush_tlb(table_address);
+        Some(frame)
+    }
+
     pub fn next_table_create<A>(&mut self, index:usize, allocator: &mut A)
                 -> &mut PageTable<L::NextLevel> where A: FrameAllocator {
         use self::entries::*;
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(HUGE_PAGE), "Mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("No frames available");
+            let (frame, zeroed) = match allocator.take_zeroed_frame() {
+                Some(frame) => (frame, true),
+                None => (allocator.allocate_frame().expect("No frames available"), false),
+            };
             self.entries[index].set(frame, PRESENT | WRITABLE);
-            self.next_table_mut(index).unwrap().zero();
+            if !zeroed {
+                self.next_table_mut(index).unwrap().zero();
+            }
         }
         self.next_table_mut(index).unwrap()
     }
 }
 
-use super::super::VirtualAddress;
+use super::super::{VirtualAddress, phys_to_virt};
 
-pub const P4_ADDR : VirtualAddress = 0xffff_ffff_ffff_f000 as VirtualAddress;
\ No newline at end of file
+pub const P4_ADDR : VirtualAddress = 0xffff_ffff_ffff_f000 as VirtualAddress;
+// Tables mapped through the recursive slot 511 of the P4 table
+const RECURSIVE_BASE : VirtualAddress = 0xffff_ff80_0000_0000 as VirtualAddress;
\ No newline at end of file
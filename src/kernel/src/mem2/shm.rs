@@ -0,0 +1,365 @@
+/*  Shared memory module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use alloc::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use super::*;
+use super::vma::*;
+use super::page::table::entries::SHARED;
+
+/// Handle of a shared memory object.
+pub type ShmId = usize;
+
+/// Why a shared memory operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// An object of that name exists already.
+    NameTaken,
+    NotFound,
+    /// The size or address is not page aligned, or the size is zero.
+    Misaligned,
+    /// Part of the target range is mapped already.
+    Overlap,
+    /// The range does not map this object.
+    NotMapped,
+    OutOfFrames,
+    /// No room in the kernel address space.
+    NoSpace,
+}
+
+/// Frames shared between the address spaces that map them. The object
+/// holds one core map reference on each frame and every mapping holds
+/// another, so frames outlive the object while a page still maps them.
+struct SharedObject {
+    name: String,
+    frames: Vec<usize>,
+    // Page table entries mapping the frames, in all address spaces
+    mapped_pages: usize,
+    // No longer found by name, but kept while it is mapped
+    unlinked: bool,
+}
+
+struct SharedObjects {
+    objects: BTreeMap<ShmId, SharedObject>,
+    // Object each frame belongs to, so that page tables can count the
+    // entries they copy and drop
+    owners: BTreeMap<usize, ShmId>,
+}
+
+impl SharedObjects {
+    fn insert(&mut self, id: ShmId, object: SharedObject) {
+        for &number in object.frames.iter() {
+            self.owners.insert(number, id);
+        }
+        self.objects.insert(id, object);
+    }
+
+    // Forget an object and hand back its frames, for the caller to drop
+    // the object's own references on them
+    fn remove(&mut self, id: ShmId) -> Vec<usize> {
+        let object = self.objects.remove(&id).unwrap();
+        for number in object.frames.iter() {
+            self.owners.remove(number);
+        }
+        object.frames
+    }
+
+    fn owner(&self, frame: &Frame) -> Option<ShmId> {
+        self.owners.get(&frame.number).cloned()
+    }
+
+    fn find(&self, name: &str) -> Option<ShmId> {
+        self.objects.iter()
+            .find(|&(_, object)| !object.unlinked && object.name == name)
+            .map(|(&id, _)| id)
+    }
+
+    fn page_mapped(&mut self, frame: &Frame) {
+        if let Some(id) = self.owner(frame) {
+            self.objects.get_mut(&id).unwrap().mapped_pages += 1;
+        }
+    }
+
+    // Frames of the object removed with its last mapped page
+    fn page_unmapped(&mut self, frame: &Frame) -> Option<Vec<usize>> {
+        let id = match self.owner(frame) {
+            Some(id) => id,
+            None => return None,
+        };
+        let last = {
+            let object = self.objects.get_mut(&id).unwrap();
+            object.mapped_pages = object.mapped_pages.checked_sub(1)
+                .expect("Shared page unmapped more often than it was mapped");
+            object.mapped_pages == 0
+        };
+        if last {
+            Some(self.remove(id))
+        }
+        else {
+            None
+        }
+    }
+}
+
+lazy_static! {
+    static ref SHARED_OBJECTS: Mutex<SharedObjects> = Mutex::new(SharedObjects {
+        objects: BTreeMap::new(),
+        owners: BTreeMap::new(),
+    });
+}
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Create an object of `size` bytes of zeroed memory. It lives until
+/// the last page mapping it is removed, or until `destroy_shared` if it
+/// is never mapped.
+pub fn create_shared(name: &str, size: usize) -> Result<ShmId, ShmError> {
+    if size == 0 || size % PAGE_SIZE != 0 {
+        return Err(ShmError::Misaligned);
+    }
+    let mut frames = Vec::with_capacity(size / PAGE_SIZE);
+    for _ in 0..(size / PAGE_SIZE) {
+        match allocate_zeroed_frame(&mut GlobalFrameAllocator) {
+            Some(frame) => frames.push(frame.number),
+            None => {
+                release_frames(&frames);
+                return Err(ShmError::OutOfFrames);
+            }
+        }
+    }
+
+    // The frames are taken first, as the lock is held under the page table lock
+    let mut objects = SHARED_OBJECTS.lock();
+    if objects.find(name).is_some() {
+        drop(objects);
+        release_frames(&frames);
+        return Err(ShmError::NameTaken);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    objects.insert(id, SharedObject {
+        name: String::from(name),
+        frames: frames,
+        mapped_pages: 0,
+        unlinked: false,
+    });
+    Ok(id)
+}
+
+/// Remove the name of an object. An object that is not mapped is freed
+/// at once, a mapped one when the last page mapping it goes.
+pub fn destroy_shared(id: ShmId) -> Result<(), ShmError> {
+    let mut objects = SHARED_OBJECTS.lock();
+    let mapped = match objects.objects.get_mut(&id) {
+        Some(object) => {
+            if object.unlinked {
+                return Err(ShmError::NotFound);
+            }
+            object.unlinked = true;
+            object.mapped_pages > 0
+        }
+        None => return Err(ShmError::NotFound),
+    };
+    if !mapped {
+        release_frames(&objects.remove(id));
+    }
+    Ok(())
+}
+
+/// Look an object up by name.
+pub fn open_shared(name: &str) -> Option<ShmId> {
+    SHARED_OBJECTS.lock().find(name)
+}
+
+/// Size of an object in bytes.
+pub fn shared_size(id: ShmId) -> Option<usize> {
+    SHARED_OBJECTS.lock().objects.get(&id).map(|object| object.frames.len() * PAGE_SIZE)
+}
+
+/// Map the whole of an object at `address` in `table` with the given
+/// permissions. The pages stay shared when the address space is cloned.
+pub fn map_shared<A>(id: ShmId, table: &mut InnerPageTable, address: VirtualAddress,
+                     flags: VmaFlags, allocator: &mut A)
+                     -> Result<(), ShmError> where A: FrameAllocator {
+    if address % PAGE_SIZE != 0 {
+        return Err(ShmError::Misaligned);
+    }
+    let mut objects = SHARED_OBJECTS.lock();
+    let object = match objects.objects.get_mut(&id) {
+        Some(object) => object,
+        None => return Err(ShmError::NotFound),
+    };
+    let start = Page::from(address);
+    let pages = object.frames.len();
+    if (0..pages).any(|i| table.translate_page(start + i).is_some()) {
+        return Err(ShmError::Overlap);
+    }
+
+    for (i, &number) in object.frames.iter().enumerate() {
+        let frame = Frame { number: number };
+        GlobalFrameAllocator::with_coremap(|coremap| coremap.get_ref(&frame));
+        table.map_to(start + i, frame, flags.entry_flags() | SHARED, allocator);
+    }
+    object.mapped_pages += pages;
+    Ok(())
+}
+
+/// Remove a mapping made by `map_shared`. The object goes away with the
+/// last page mapping it, whichever address space that is in.
+pub fn unmap_shared<A>(id: ShmId, table: &mut InnerPageTable, address: VirtualAddress,
+                       allocator: &mut A)
+                       -> Result<(), ShmError> where A: FrameAllocator {
+    let start = Page::from(address);
+    let pages = {
+        let objects = SHARED_OBJECTS.lock();
+        let object = match objects.objects.get(&id) {
+            Some(object) => object,
+            None => return Err(ShmError::NotFound),
+        };
+        for (i, &number) in object.frames.iter().enumerate() {
+            match table.translate_page(start + i) {
+                Some(ref frame) if frame.number == number => {}
+                _ => return Err(ShmError::NotMapped),
+            }
+        }
+        object.frames.len()
+    };
+
+    // Each unmap drops the mapping's reference on its frame and counts
+    // the page as unmapped, which takes the lock again
+    for i in 0..pages {
+        table.unmap(start + i, allocator);
+    }
+    Ok(())
+}
+
+/// Map an object into the kernel half, wherever there is room.
+pub fn map_shared_kernel(id: ShmId, flags: VmaFlags) -> Result<VirtualAddress, ShmError> {
+    let size = match shared_size(id) {
+        Some(size) => size,
+        None => return Err(ShmError::NotFound),
+    };
+    with_kernel_vmas(|vmas, table| {
+        let mut allocator = GlobalFrameAllocator;
+        let start = match vmas.map(None, size, flags, Backing::Reserved, Sharing::Shared,
+                                   "shared memory") {
+            Ok(start) => start,
+            Err(_) => return Err(ShmError::NoSpace),
+        };
+        if let Err(error) = map_shared(id, table, start, flags, &mut allocator) {
+            vmas.unmap(start, size, table, &mut allocator).unwrap();
+            return Err(error);
+        }
+        Ok(start)
+    })
+}
+
+/// Remove a mapping made by `map_shared_kernel`.
+pub fn unmap_shared_kernel(id: ShmId, address: VirtualAddress) -> Result<(), ShmError> {
+    let size = match shared_size(id) {
+        Some(size) => size,
+        None => return Err(ShmError::NotFound),
+    };
+    with_kernel_vmas(|vmas, table| {
+        let mut allocator = GlobalFrameAllocator;
+        unmap_shared(id, table, address, &mut allocator)?;
+        vmas.unmap(address, size, table, &mut allocator).map_err(|_| ShmError::NotMapped)
+    })
+}
+
+/// Count another page table entry mapping the shared `frame`, for
+/// entries copied into a cloned address space.
+pub fn page_mapped(frame: &Frame) {
+    SHARED_OBJECTS.lock().page_mapped(frame);
+}
+
+/// Count a page table entry mapping the shared `frame` as removed. The
+/// object is freed with its last mapped page.
+pub fn page_unmapped(frame: &Frame) {
+    if let Some(frames) = SHARED_OBJECTS.lock().page_unmapped(frame) {
+        release_frames(&frames);
+    }
+}
+
+// Drop the object's own references on its frames
+fn release_frames(frames: &[usize]) {
+    for &number in frames.iter() {
+        GlobalFrameAllocator.deallocate_frame(Frame { number: number });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects() -> SharedObjects {
+        SharedObjects {
+            objects: BTreeMap::new(),
+            owners: BTreeMap::new(),
+        }
+    }
+
+    fn object(name: &str, frames: Vec<usize>) -> SharedObject {
+        SharedObject {
+            name: String::from(name),
+            frames: frames,
+            mapped_pages: 0,
+            unlinked: false,
+        }
+    }
+
+    #[test]
+    fn insert_and_remove_track_owners() {
+        let mut objects = objects();
+        objects.insert(1, object("a", vec![10, 11]));
+        objects.insert(2, object("b", vec![12]));
+        assert_eq!(objects.find("a"), Some(1));
+        assert_eq!(objects.find("b"), Some(2));
+        assert_eq!(objects.owner(&Frame { number: 11 }), Some(1));
+        assert_eq!(objects.owner(&Frame { number: 13 }), None);
+
+        assert_eq!(objects.remove(1), vec![10, 11]);
+        assert_eq!(objects.find("a"), None);
+        assert_eq!(objects.owner(&Frame { number: 10 }), None);
+        assert_eq!(objects.owner(&Frame { number: 12 }), Some(2));
+    }
+
+    #[test]
+    fn unlinked_object_is_not_found() {
+        let mut objects = objects();
+        objects.insert(1, object("a", vec![10]));
+        objects.objects.get_mut(&1).unwrap().unlinked = true;
+        assert_eq!(objects.find("a"), None);
+        assert_eq!(objects.owner(&Frame { number: 10 }), Some(1));
+    }
+
+    #[test]
+    fn object_removed_with_last_unmapped_page() {
+        let mut objects = objects();
+        objects.insert(1, object("a", vec![10, 11]));
+        objects.page_mapped(&Frame { number: 10 });
+        objects.page_mapped(&Frame { number: 11 });
+        // A copy of the first page in a cloned address space
+        objects.page_mapped(&Frame { number: 10 });
+        assert_eq!(objects.objects[&1].mapped_pages, 3);
+
+        assert_eq!(objects.page_unmapped(&Frame { number: 10 }), None);
+        assert_eq!(objects.page_unmapped(&Frame { number: 11 }), None);
+        assert_eq!(objects.page_unmapped(&Frame { number: 10 }), Some(vec![10, 11]));
+        assert!(objects.objects.is_empty());
+        assert!(objects.owners.is_empty());
+        // Pages of frames no object owns are not counted
+        assert_eq!(objects.page_unmapped(&Frame { number: 10 }), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unmapping_more_pages_than_mapped_panics() {
+        let mut objects = objects();
+        objects.insert(1, object("a", vec![10]));
+        objects.page_unmapped(&Frame { number: 10 });
+    }
+}
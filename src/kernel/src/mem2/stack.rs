@@ -0,0 +1,114 @@
+/*  Kernel stack module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use super::*;
+use super::vma::*;
+
+/// Stack size for callers without particular needs.
+pub const DEFAULT_STACK_PAGES: usize = 4;
+
+/// Name of the regions holding kernel stacks.
+pub const KERNEL_STACK_NAME: &'static str = "kernel stack";
+
+/// Freed stacks kept mapped for reuse.
+const MAX_CACHED_STACKS: usize = 8;
+
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtualAddress,
+    bottom: VirtualAddress,
+    owner: &'static str,
+}
+
+impl Stack {
+    fn new(top: VirtualAddress, bottom: VirtualAddress, owner: &'static str) -> Stack {
+        assert!(top > bottom);
+        Stack {
+            top: top,
+            bottom: bottom,
+            owner: owner,
+        }
+    }
+
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    pub fn pages(&self) -> usize {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
+
+    fn guard_page(&self) -> VirtualAddress {
+        self.bottom - PAGE_SIZE
+    }
+}
+
+lazy_static! {
+    /// Owner of every stack in use, by the address of its guard page.
+    static ref STACK_GUARDS: Mutex<BTreeMap<VirtualAddress, &'static str>> =
+        Mutex::new(BTreeMap::new());
+    static ref FREE_STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+}
+
+/// Allocate a kernel stack of `pages` pages for `owner`, with an
+/// unmapped guard page below it. Freed stacks of the same size are
+/// reused first.
+pub fn alloc_stack(pages: usize, owner: &'static str) -> Option<Stack> {
+    if pages == 0 {
+        return None;
+    }
+    let cached = {
+        let mut free = FREE_STACKS.lock();
+        match free.iter().position(|stack| stack.pages() == pages) {
+            Some(index) => Some(free.swap_remove(index)),
+            None => None,
+        }
+    };
+    let mut stack = match cached {
+        Some(stack) => stack,
+        None => {
+            match map_guarded(pages * PAGE_SIZE, VMA_READ | VMA_WRITE | VMA_GROWS_DOWN, KERNEL_STACK_NAME) {
+                Some(bottom) => Stack::new(bottom + pages * PAGE_SIZE, bottom, owner),
+                None => return None,
+            }
+        }
+    };
+    stack.owner = owner;
+    STACK_GUARDS.lock().insert(stack.guard_page(), owner);
+    Some(stack)
+}
+
+/// Give a stack back. It stays mapped for reuse while the cache of
+/// free stacks has room.
+pub fn free_stack(stack: Stack) {
+    STACK_GUARDS.lock().remove(&stack.guard_page());
+    {
+        let mut free = FREE_STACKS.lock();
+        if free.len() < MAX_CACHED_STACKS {
+            free.push(stack);
+            return;
+        }
+    }
+    unmap_guarded(stack.bottom, stack.top - stack.bottom);
+}
+
+/// Owner of the stack whose guard page contains `address`. Called from
+/// the fault handler, so it gives up if the registry is locked.
+pub fn guard_owner(address: VirtualAddress) -> Option<&'static str> {
+    match STACK_GUARDS.try_lock() {
+        Some(guards) => guards.get(&(address & !(PAGE_SIZE - 1))).cloned(),
+        None => None,
+    }
+}
@@ -0,0 +1,405 @@
+/*  Swap module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::slice;
+use dev::block::{BlockDevice, BlockError};
+use super::*;
+use super::page::table::entries::*;
+
+/// Pages pushed out at once when a fault finds no free frame.
+pub const SWAP_OUT_BATCH: usize = 16;
+
+// Signature `mkswap` leaves at the end of the first page, which holds
+// the header and is never used as a slot
+const SWAP_SIGNATURE: &'static [u8] = b"SWAPSPACE2";
+const HEADER_SLOT: usize = 0;
+
+/// Why a page could not be swapped.
+#[derive(Debug, Clone, Copy)]
+pub enum SwapError {
+    NoDevice,
+    /// Another fault is using the swap area.
+    Busy,
+    /// The device blocks do not divide a page.
+    BadBlockSize,
+    /// The device has no swap signature, so it may hold other data.
+    NoSignature,
+    OutOfFrames,
+    Device(BlockError),
+}
+
+/// Swap area spanning a whole block device, in page sized slots.
+struct SwapArea {
+    device: Box<BlockDevice + Send>,
+    blocks_per_slot: usize,
+    // Page table entries holding each slot, 0 if it is free
+    slot_refs: Vec<u8>,
+    free_slots: usize,
+    next_slot: usize,
+    // Where the next scan for cold pages starts
+    hand: VirtualAddress,
+}
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+enum Reclaim {
+    Freed,
+    Skipped,
+    Full,
+}
+
+impl SwapArea {
+    // Every slot free but the header, which is held for good so that it
+    // is never handed out
+    fn new(device: Box<BlockDevice + Send>, blocks_per_slot: usize, slots: usize) -> SwapArea {
+        let mut slot_refs = vec![0; slots];
+        slot_refs[HEADER_SLOT] = 1;
+        SwapArea {
+            device: device,
+            blocks_per_slot: blocks_per_slot,
+            slot_refs: slot_refs,
+            free_slots: slots - 1,
+            next_slot: HEADER_SLOT + 1,
+            hand: USER_START,
+        }
+    }
+
+    fn alloc_slot(&mut self) -> Option<usize> {
+        if self.free_slots == 0 {
+            return None;
+        }
+        let count = self.slot_refs.len();
+        for i in 0..count {
+            let slot = (self.next_slot + i) % count;
+            if self.slot_refs[slot] == 0 {
+                self.slot_refs[slot] = 1;
+                self.free_slots -= 1;
+                self.next_slot = slot + 1;
+                return Some(slot);
+            }
+        }
+        None
+    }
+
+    fn dup_slot(&mut self, slot: usize) {
+        assert!(self.slot_refs[slot] > 0 && self.slot_refs[slot] < u8::max_value(),
+            "Cannot share swap slot {}", slot);
+        self.slot_refs[slot] += 1;
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        assert!(self.slot_refs[slot] > 0, "Freeing free swap slot {}", slot);
+        self.slot_refs[slot] -= 1;
+        if self.slot_refs[slot] == 0 {
+            self.free_slots += 1;
+        }
+    }
+
+    fn read_page(&mut self, slot: usize, frame: &Frame) -> Result<(), SwapError> {
+        let buffer = unsafe {
+            slice::from_raw_parts_mut(phys_to_virt(frame.start_address()) as *mut u8, PAGE_SIZE)
+        };
+        self.device.read_blocks(slot * self.blocks_per_slot, buffer).map_err(SwapError::Device)
+    }
+
+    fn write_page(&mut self, slot: usize, frame: &Frame) -> Result<(), SwapError> {
+        let buffer = unsafe {
+            slice::from_raw_parts(phys_to_virt(frame.start_address()) as *const u8, PAGE_SIZE)
+        };
+        self.device.write_blocks(slot * self.blocks_per_slot, buffer).map_err(SwapError::Device)
+    }
+
+    // Push `page` of a swappable region out if it is cold. Recently
+    // used pages lose their accessed bit and get another round. Pages
+    // that were never written are still zero and are simply dropped, to
+    // be backed again by the fault path. Shared memory objects stay.
+    fn reclaim(&mut self, table: &mut ActivePageTable, page: Page) -> Reclaim {
+        let entry = table.entry_mut(page).expect("Mapped page without a level 1 table");
+        let flags = entry.flags();
+        if flags.contains(SHARED) {
+            return Reclaim::Skipped;
+        }
+        let frame = entry.pointed_frame().expect("Reclaiming a page that is not present");
+        let shared = GlobalFrameAllocator::with_coremap(|coremap| {
+            !coremap.contains(&frame) || coremap.refcount(&frame) > 1
+        });
+        if shared {
+            return Reclaim::Skipped;
+        }
+        if flags.contains(ACCESSED) {
+            entry.set(frame, flags - ACCESSED);
+            flush_tlb(page.start_address());
+            return Reclaim::Skipped;
+        }
+
+        if flags.contains(DIRTY) {
+            let slot = match self.alloc_slot() {
+                Some(slot) => slot,
+                None => return Reclaim::Full,
+            };
+            if self.write_page(slot, &frame).is_err() {
+                self.free_slot(slot);
+                return Reclaim::Full;
+            }
+            // The frame is no longer shared, so the page can be writable again
+            let mut swap_flags = flags & (WRITABLE | USER_ACCESSIBLE | NO_EXECUTE | COPY_ON_WRITE);
+            if swap_flags.contains(COPY_ON_WRITE) {
+                swap_flags.remove(COPY_ON_WRITE);
+                swap_flags.insert(WRITABLE);
+            }
+            entry.set_swapped(slot, swap_flags);
+        }
+        else {
+            entry.set_unused();
+        }
+        flush_tlb(page.start_address());
+        GlobalFrameAllocator.deallocate_frame(frame);
+        Reclaim::Freed
+    }
+}
+
+/// Swap to the whole of `device`, which must have been set up with
+/// `mkswap`. Devices without its signature are refused, so that a disk
+/// holding data is never overwritten. Returns the number of page slots.
+pub fn enable_swap(mut device: Box<BlockDevice + Send>) -> Result<usize, SwapError> {
+    let block_size = device.block_size();
+    if block_size == 0 || block_size > PAGE_SIZE || PAGE_SIZE % block_size != 0 {
+        return Err(SwapError::BadBlockSize);
+    }
+    let blocks_per_slot = PAGE_SIZE / block_size;
+    let slots = device.block_count() / blocks_per_slot;
+    if slots <= HEADER_SLOT + 1 {
+        return Err(SwapError::NoSignature);
+    }
+
+    let mut header = vec![0u8; PAGE_SIZE];
+    device.read_blocks(HEADER_SLOT * blocks_per_slot, &mut header).map_err(SwapError::Device)?;
+    if &header[PAGE_SIZE - SWAP_SIGNATURE.len()..] != SWAP_SIGNATURE {
+        return Err(SwapError::NoSignature);
+    }
+
+    *SWAP.lock() = Some(SwapArea::new(device, blocks_per_slot, slots));
+    Ok(slots - 1)
+}
+
+/// Write up to `wanted` cold anonymous pages of the running address
+/// space out to swap and free their frames. Only pages of anonymous
+/// regions whose frame is not shared are considered. Returns the
+/// number of frames freed.
+pub fn swap_out(table: &mut ActivePageTable, wanted: usize) -> usize {
+    super::vma::try_with_user_vmas(|vmas| swap_out_from(table, vmas, wanted)).unwrap_or(0)
+}
+
+// Only private anonymous memory is swapped. Other regions are mapped by
+// their owners, and shared ones may be mapped elsewhere.
+fn swappable(vma: &Vma) -> bool {
+    vma.backing == Backing::Anonymous && vma.sharing == Sharing::Private
+}
+
+fn swap_out_from(table: &mut ActivePageTable, vmas: &VmaManager, wanted: usize) -> usize {
+    let mut swap = match SWAP.try_lock() {
+        Some(swap) => swap,
+        None => return 0,
+    };
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return 0,
+    };
+
+    let mut page = Page::from(area.hand);
+    let mut freed = 0;
+    // The second pass finds the pages the first one took the accessed bit from
+    let mut wraps = 0;
+    while freed < wanted && wraps < 2 {
+        let address = page.start_address();
+        let vma = match vmas.regions().find(|vma| vma.end > address && swappable(vma)) {
+            Some(vma) => *vma,
+            None => {
+                wraps += 1;
+                page = Page::from(USER_START);
+                continue;
+            }
+        };
+        if address < vma.start {
+            page = Page::from(vma.start);
+        }
+        let next = match table.next_mapped_page(page, Page::from(vma.end - 1)) {
+            Some(next) => next,
+            None => {
+                page = Page::from(vma.end);
+                continue;
+            }
+        };
+        page = next + 1;
+        match area.reclaim(table, next) {
+            Reclaim::Freed => freed += 1,
+            Reclaim::Skipped => {}
+            Reclaim::Full => break,
+        }
+    }
+    area.hand = page.start_address();
+    freed
+}
+
+/// Bring a swapped out page back. Returns false if `page` is not swapped.
+pub fn swap_in(table: &mut ActivePageTable, page: Page) -> Result<bool, SwapError> {
+    let (slot, flags) = match table.entry_mut(page).and_then(|entry| entry.swap_slot()) {
+        Some(swapped) => swapped,
+        None => return Ok(false),
+    };
+    let frame = match allocate_frame(table) {
+        Some(frame) => frame,
+        None => return Err(SwapError::OutOfFrames),
+    };
+    {
+        let mut swap = match SWAP.try_lock() {
+            Some(swap) => swap,
+            None => {
+                GlobalFrameAllocator.deallocate_frame(frame);
+                return Err(SwapError::Busy);
+            }
+        };
+        let area = swap.as_mut().expect("Swapped page without a swap area");
+        if let Err(error) = area.read_page(slot, &frame) {
+            GlobalFrameAllocator.deallocate_frame(frame);
+            return Err(error);
+        }
+        area.free_slot(slot);
+    }
+
+    record_mapping(&frame, page.start_address());
+    // Dirty, as the contents have to go back to swap if the page is pushed out again
+    table.entry_mut(page).unwrap().set(frame, flags | PRESENT | DIRTY);
+    flush_tlb(page.start_address());
+    Ok(true)
+}
+
+/// Frame for the fault path, pushing cold pages out to swap if no
+/// frame is left.
+pub fn allocate_frame(table: &mut ActivePageTable) -> Option<Frame> {
+    if let Some(frame) = GlobalFrameAllocator.allocate_frame() {
+        return Some(frame);
+    }
+    if swap_out(table, SWAP_OUT_BATCH) == 0 {
+        return None;
+    }
+    GlobalFrameAllocator.allocate_frame()
+}
+
+/// Take another reference on a slot, for a page table entry copied
+/// into a new address space.
+pub fn dup_slot(slot: usize) {
+    SWAP.lock().as_mut().expect("Swapped page without a swap area").dup_slot(slot);
+}
+
+/// Drop a page table entry's reference on a slot.
+pub fn free_slot(slot: usize) {
+    SWAP.lock().as_mut().expect("Swapped page without a swap area").free_slot(slot);
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use super::*;
+
+    const BLOCK_SIZE: usize = 512;
+
+    // Block device in memory
+    struct RamDisk {
+        data: Vec<u8>,
+    }
+
+    impl BlockDevice for RamDisk {
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+
+        fn block_count(&self) -> usize {
+            self.data.len() / BLOCK_SIZE
+        }
+
+        fn read_blocks(&mut self, start: usize, buffer: &mut [u8]) -> Result<(), BlockError> {
+            let offset = start * BLOCK_SIZE;
+            if offset + buffer.len() > self.data.len() {
+                return Err(BlockError::OutOfRange);
+            }
+            buffer.copy_from_slice(&self.data[offset..offset + buffer.len()]);
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, start: usize, buffer: &[u8]) -> Result<(), BlockError> {
+            let offset = start * BLOCK_SIZE;
+            if offset + buffer.len() > self.data.len() {
+                return Err(BlockError::OutOfRange);
+            }
+            self.data[offset..offset + buffer.len()].copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    fn area(slots: usize) -> SwapArea {
+        let disk = RamDisk { data: vec![0; slots * PAGE_SIZE] };
+        SwapArea::new(Box::new(disk), PAGE_SIZE / BLOCK_SIZE, slots)
+    }
+
+    #[test]
+    fn alloc_slot_skips_the_header_until_full() {
+        let mut area = area(4);
+        assert_eq!(area.free_slots, 3);
+        assert_eq!(area.alloc_slot(), Some(1));
+        assert_eq!(area.alloc_slot(), Some(2));
+        assert_eq!(area.alloc_slot(), Some(3));
+        assert_eq!(area.free_slots, 0);
+        assert_eq!(area.alloc_slot(), None);
+
+        area.free_slot(2);
+        assert_eq!(area.free_slots, 1);
+        assert_eq!(area.alloc_slot(), Some(2));
+    }
+
+    #[test]
+    fn shared_slot_freed_with_last_reference() {
+        let mut area = area(2);
+        let slot = area.alloc_slot().unwrap();
+        area.dup_slot(slot);
+        area.dup_slot(slot);
+        assert_eq!(area.slot_refs[slot], 3);
+
+        area.free_slot(slot);
+        area.free_slot(slot);
+        assert_eq!(area.free_slots, 0);
+        assert_eq!(area.alloc_slot(), None);
+        area.free_slot(slot);
+        assert_eq!(area.free_slots, 1);
+        assert_eq!(area.alloc_slot(), Some(slot));
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_slot_twice_panics() {
+        let mut area = area(2);
+        let slot = area.alloc_slot().unwrap();
+        area.free_slot(slot);
+        area.free_slot(slot);
+    }
+
+    #[test]
+    #[should_panic]
+    fn dup_free_slot_panics() {
+        area(2).dup_slot(1);
+    }
+
+    #[test]
+    fn enable_swap_refuses_device_without_signature() {
+        let disk = RamDisk { data: vec![0; 4 * PAGE_SIZE] };
+        match enable_swap(Box::new(disk)) {
+            Err(SwapError::NoSignature) => {}
+            other => panic!("Unsigned device gave {:?}", other),
+        }
+    }
+}
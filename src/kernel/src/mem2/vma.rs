@@ -0,0 +1,654 @@
+/*  Virtual memory area module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use alloc::btree_map::{self, BTreeMap};
+use alloc::vec::Vec;
+use core::mem;
+use super::*;
+use super::page::table::entries::*;
+
+bitflags! {
+    flags VmaFlags: u8 {
+        const VMA_READ =       1 << 0,
+        const VMA_WRITE =      1 << 1,
+        const VMA_EXEC =       1 << 2,
+        const VMA_USER =       1 << 3,
+        const VMA_GROWS_DOWN = 1 << 4,
+    }
+}
+
+impl VmaFlags {
+    /// Page table flags for the pages of a region.
+    pub fn entry_flags(&self) -> EntryFlags {
+        let mut flags = PRESENT;
+        if self.contains(VMA_WRITE) {
+            flags = flags | WRITABLE;
+        }
+        if self.contains(VMA_USER) {
+            flags = flags | USER_ACCESSIBLE;
+        }
+        if !self.contains(VMA_EXEC) {
+            flags = flags | NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Where the contents of a region come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed memory. Each page gets a frame the first time it is
+    /// touched, unless the region is populated up front.
+    Anonymous,
+    /// A file starting at `offset`, paged in by the file system.
+    File { inode: usize, offset: usize },
+    /// Address space set aside for an owner that maps pages itself.
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    Private,
+    Shared,
+}
+
+/// A virtual range `[start, end)` and what it is used for.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub flags: VmaFlags,
+    pub backing: Backing,
+    pub sharing: Sharing,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    // Cut the region at `address`, returning the upper part
+    fn split_off(&mut self, address: VirtualAddress) -> Vma {
+        let mut upper = *self;
+        upper.start = address;
+        if let Backing::File { inode, offset } = self.backing {
+            upper.backing = Backing::File {
+                inode: inode,
+                offset: offset + (address - self.start),
+            };
+        }
+        self.end = address;
+        upper
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Address or size is not page aligned, or the size is zero.
+    Misaligned,
+    /// The range lies outside the managed window.
+    OutOfRange,
+    /// The range overlaps an existing region.
+    Overlap,
+    /// No free range is large enough.
+    NoSpace,
+    /// Part of the range belongs to no region.
+    NotMapped,
+    /// No frames were left to back an anonymous region.
+    OutOfFrames,
+}
+
+/// Regions of one address space, ordered by start address. Every
+/// operation updates the page table it is given to match.
+#[derive(Clone)]
+pub struct VmaManager {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    regions: BTreeMap<VirtualAddress, Vma>,
+}
+
+impl VmaManager {
+    /// Manager handing out addresses in `[start, end)`. User address
+    /// spaces use `USER_START` to `USER_END`.
+    pub fn new(start: VirtualAddress, end: VirtualAddress) -> VmaManager {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0 && start < end,
+            "Invalid VMA window {:#x} - {:#x}", start, end);
+        VmaManager {
+            start: start,
+            end: end,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    pub fn regions(&self) -> btree_map::Values<VirtualAddress, Vma> {
+        self.regions.values()
+    }
+
+    /// Region containing `address`.
+    pub fn find(&self, address: VirtualAddress) -> Option<&Vma> {
+        // Regions starting at or below `address`, minding the top of memory
+        let mut candidates = match address.checked_add(1) {
+            Some(bound) => self.regions.range(..bound),
+            None => self.regions.range(..),
+        };
+        match candidates.next_back() {
+            Some((_, vma)) if vma.contains(address) => Some(vma),
+            _ => None,
+        }
+    }
+
+    /// Lowest free range of `size` bytes aligned to `align`.
+    pub fn find_free(&self, size: usize, align: usize) -> Option<VirtualAddress> {
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE, "Invalid VMA alignment {}", align);
+        let align_up = |address: VirtualAddress| (address + align - 1) & !(align - 1);
+        let mut candidate = align_up(self.start);
+        for vma in self.regions.values() {
+            if vma.start >= candidate && vma.start - candidate >= size {
+                return Some(candidate);
+            }
+            if vma.end > candidate {
+                candidate = align_up(vma.end);
+            }
+        }
+        if candidate <= self.end && self.end - candidate >= size {
+            Some(candidate)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Create a region of `size` bytes at `address`, or wherever there
+    /// is room if no address is given. No pages are mapped yet: the
+    /// fault handler backs anonymous regions as they are touched.
+    pub fn map(&mut self, address: Option<VirtualAddress>, size: usize, flags: VmaFlags,
+               backing: Backing, sharing: Sharing, name: &'static str)
+               -> Result<VirtualAddress, VmaError> {
+        let start = match address {
+            Some(address) => {
+                self.check_range(address, size)?;
+                if !self.is_free(address, size) {
+                    return Err(VmaError::Overlap);
+                }
+                address
+            }
+            None => {
+                if size == 0 || size % PAGE_SIZE != 0 {
+                    return Err(VmaError::Misaligned);
+                }
+                match self.find_free(size, PAGE_SIZE) {
+                    Some(address) => address,
+                    None => return Err(VmaError::NoSpace),
+                }
+            }
+        };
+
+        self.regions.insert(start, Vma {
+            start: start,
+            end: start + size,
+            flags: flags,
+            backing: backing,
+            sharing: sharing,
+            name: name,
+        });
+        Ok(start)
+    }
+
+    /// Create an anonymous region of `size` bytes with a guard region
+    /// of one page right below it, returning the start of the region.
+    pub fn map_guarded(&mut self, size: usize, flags: VmaFlags, name: &'static str)
+                       -> Result<VirtualAddress, VmaError> {
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(VmaError::Misaligned);
+        }
+        let guard = match self.find_free(size + PAGE_SIZE, PAGE_SIZE) {
+            Some(guard) => guard,
+            None => return Err(VmaError::NoSpace),
+        };
+        self.map(Some(guard), PAGE_SIZE, VmaFlags::empty(), Backing::Reserved,
+                 Sharing::Private, "guard page")?;
+        self.map(Some(guard + PAGE_SIZE), size, flags, Backing::Anonymous, Sharing::Private, name)
+    }
+
+    /// Back every page of the anonymous region starting at `address`
+    /// with a zeroed frame now, for memory that must not fault, like
+    /// kernel stacks. Nothing stays mapped if frames run out.
+    pub fn populate<A>(&self, address: VirtualAddress, table: &mut InnerPageTable,
+                       allocator: &mut A) -> Result<(), VmaError> where A: FrameAllocator {
+        match self.regions.get(&address) {
+            Some(vma) if vma.backing == Backing::Anonymous => populate(vma, table, allocator),
+            _ => Err(VmaError::NotMapped),
+        }
+    }
+
+    /// Remove `[address, address + size)` from the regions it touches,
+    /// splitting regions that straddle its ends, and unmap its pages.
+    /// The frames of reserved regions belong to their owner, so they are
+    /// unmapped without being freed.
+    pub fn unmap<A>(&mut self, address: VirtualAddress, size: usize,
+                    table: &mut InnerPageTable, allocator: &mut A)
+                    -> Result<(), VmaError> where A: FrameAllocator {
+        self.check_range(address, size)?;
+        let keys = self.split_range(address, address + size);
+        if keys.is_empty() {
+            return Err(VmaError::NotMapped);
+        }
+        for key in keys {
+            let vma = self.regions.remove(&key).unwrap();
+            let (start, end) = (Page::from(vma.start), Page::from(vma.end - 1));
+            if vma.backing == Backing::Reserved {
+                let mut page = start;
+                while let Some(mapped) = table.next_mapped_page(page, end) {
+                    table.unmap_page(mapped, allocator);
+                    page = mapped + 1;
+                }
+            }
+            else {
+                table.unmap_range(start, end, allocator);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the permissions of `[address, address + size)`, which
+    /// must be covered by regions, along with its mapped pages.
+    /// Copy-on-write pages stay read-only until they are written.
+    pub fn protect<A>(&mut self, address: VirtualAddress, size: usize, flags: VmaFlags,
+                      table: &mut InnerPageTable, allocator: &mut A)
+                      -> Result<(), VmaError> where A: FrameAllocator {
+        self.check_range(address, size)?;
+        let keys = self.split_range(address, address + size);
+        let mut covered = address;
+        for key in keys.iter() {
+            if *key != covered {
+                return Err(VmaError::NotMapped);
+            }
+            covered = self.regions[key].end;
+        }
+        if covered != address + size {
+            return Err(VmaError::NotMapped);
+        }
+
+        for key in keys.iter() {
+            let vma = self.regions.get_mut(key).unwrap();
+            vma.flags = (flags - VMA_GROWS_DOWN) | (vma.flags & VMA_GROWS_DOWN);
+            reprotect(vma, table, allocator);
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, address: VirtualAddress, size: usize) -> Result<(), VmaError> {
+        if address % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || size == 0 {
+            return Err(VmaError::Misaligned);
+        }
+        if address < self.start || address > self.end || self.end - address < size {
+            return Err(VmaError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn is_free(&self, address: VirtualAddress, size: usize) -> bool {
+        // Regions are disjoint, so only the last one starting below the end matters
+        match self.regions.range(..address + size).next_back() {
+            Some((_, vma)) => vma.end <= address,
+            None => true,
+        }
+    }
+
+    // Split the regions straddling `start` or `end`, then list the
+    // regions between them
+    fn split_range(&mut self, start: VirtualAddress, end: VirtualAddress) -> Vec<VirtualAddress> {
+        self.split_at(start);
+        self.split_at(end);
+        self.regions.range(start..end).map(|(&key, _)| key).collect()
+    }
+
+    fn split_at(&mut self, address: VirtualAddress) {
+        let upper = match self.regions.range_mut(..address).next_back() {
+            Some((_, vma)) if vma.end > address => vma.split_off(address),
+            _ => return,
+        };
+        self.regions.insert(address, upper);
+    }
+}
+
+// Back every page of an anonymous region with a zeroed frame
+fn populate<A>(vma: &Vma, table: &mut InnerPageTable, allocator: &mut A)
+               -> Result<(), VmaError> where A: FrameAllocator {
+    let start_page = Page::from(vma.start);
+    for i in 0..(vma.size() / PAGE_SIZE) {
+        match allocate_zeroed_frame(allocator) {
+            Some(frame) => {
+                table.map_to(start_page + i, frame, vma.flags.entry_flags(), allocator);
+            }
+            None => {
+                if i > 0 {
+                    table.unmap_range(start_page, start_page + (i - 1), allocator);
+                }
+                return Err(VmaError::OutOfFrames);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Rewrite the flags of the mapped pages of a region
+fn reprotect<A>(vma: &Vma, table: &mut InnerPageTable, allocator: &mut A)
+                where A: FrameAllocator {
+    let flags = vma.flags.entry_flags();
+    for page in range_inclusive(Page::from(vma.start), Page::from(vma.end - 1)) {
+        if table.translate_page(page).is_none() {
+            // Swapped out pages come back with the new permissions
+            if let Some(entry) = table.entry_mut(page) {
+                if let Some((slot, _)) = entry.swap_slot() {
+                    entry.set_swapped(slot, flags & (WRITABLE | USER_ACCESSIBLE | NO_EXECUTE));
+                }
+            }
+            continue;
+        }
+        while table.split_huge_page(page, allocator) {}
+        let entry = table.entry_mut(page).unwrap();
+        let frame = entry.pointed_frame().unwrap();
+        let mut page_flags = flags;
+        if entry.flags().contains(COPY_ON_WRITE) && flags.contains(WRITABLE) {
+            page_flags.remove(WRITABLE);
+            page_flags.insert(COPY_ON_WRITE);
+        }
+        entry.set(frame, page_flags);
+        flush_tlb(page.start_address());
+    }
+}
+
+/// Regions of the kernel half, which every address space shares.
+static KERNEL_VMAS: Mutex<Option<VmaManager>> = Mutex::new(None);
+
+/// Regions of the user half of the running address space. They are
+/// swapped along with the page table by `switch_address_space`. Never
+/// locked together with `KERNEL_VMAS`.
+static USER_VMAS: Mutex<Option<VmaManager>> = Mutex::new(None);
+
+/// Start handing out kernel ranges, and user ranges of the boot address
+/// space. Needs the heap for the trees.
+pub fn init_kernel_vmas() {
+    let mut vmas = KERNEL_VMAS.lock();
+    assert!(vmas.is_none(), "Kernel VMAs already initialized!");
+    *vmas = Some(VmaManager::new(KERNEL_DYNAMIC_START, KERNEL_DYNAMIC_END));
+    *USER_VMAS.lock() = Some(VmaManager::new(USER_START, USER_END));
+}
+
+/// Run `f` on the kernel regions and the active page table. Takes the
+/// region lock before the table lock.
+pub fn with_kernel_vmas<F, R>(f: F) -> R
+                              where F: FnOnce(&mut VmaManager, &mut ActivePageTable) -> R {
+    let mut vmas = KERNEL_VMAS.lock();
+    let mut table = ACTIVE_TABLE.lock();
+    f(vmas.as_mut().expect("Kernel VMAs not initialized"),
+      table.as_mut().expect("Page tables not initialized"))
+}
+
+/// Run `f` on the user regions and the page table of the running
+/// address space. Takes the region lock before the table lock.
+pub fn with_user_vmas<F, R>(f: F) -> R
+                            where F: FnOnce(&mut VmaManager, &mut ActivePageTable) -> R {
+    let mut vmas = USER_VMAS.lock();
+    let mut table = ACTIVE_TABLE.lock();
+    f(vmas.as_mut().expect("User VMAs not initialized"),
+      table.as_mut().expect("Page tables not initialized"))
+}
+
+/// Run `f` on the user regions of the running address space, unless
+/// they are locked. For paths holding the table lock already, which
+/// must not wait for the region lock.
+pub fn try_with_user_vmas<F, R>(f: F) -> Option<R> where F: FnOnce(&VmaManager) -> R {
+    match USER_VMAS.try_lock() {
+        Some(vmas) => vmas.as_ref().map(f),
+        None => None,
+    }
+}
+
+/// Region holding `address`, in the kernel half or in the user half of
+/// the running address space. Called from the fault handler, so it
+/// gives up instead of waiting if the regions are locked.
+pub fn find_vma(address: VirtualAddress) -> Result<Option<Vma>, FaultError> {
+    let vmas = if address >= KERNEL_VMA { &KERNEL_VMAS } else { &USER_VMAS };
+    match vmas.try_lock() {
+        Some(vmas) => Ok(vmas.as_ref().and_then(|vmas| vmas.find(address)).cloned()),
+        None => Err(FaultError::TablesLocked),
+    }
+}
+
+/// Address space that is not running: its page table and the regions
+/// of its user half.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    vmas: VmaManager,
+}
+
+impl AddressSpace {
+    pub fn vmas(&self) -> &VmaManager {
+        &self.vmas
+    }
+}
+
+/// New address space with an empty user half.
+pub fn create_address_space() -> AddressSpace {
+    let mut table_guard = ACTIVE_TABLE.lock();
+    let active_table = table_guard.as_mut().expect("Page tables not initialized");
+    AddressSpace {
+        table: active_table.create_table(&mut GlobalFrameAllocator),
+        vmas: VmaManager::new(USER_START, USER_END),
+    }
+}
+
+/// Copy-on-write duplicate of the running address space, with a copy
+/// of its regions.
+pub fn clone_address_space() -> AddressSpace {
+    with_user_vmas(|vmas, table| {
+        AddressSpace {
+            table: table.clone_table(&mut GlobalFrameAllocator),
+            vmas: vmas.clone(),
+        }
+    })
+}
+
+/// Make `space` the running address space, returning the old one.
+pub fn switch_address_space(space: AddressSpace) -> AddressSpace {
+    with_user_vmas(|vmas, table| {
+        let old_vmas = mem::replace(vmas, space.vmas);
+        AddressSpace {
+            table: table.switch(space.table),
+            vmas: old_vmas,
+        }
+    })
+}
+
+/// Tear down an address space that is not running, freeing its tables,
+/// its user frames and its swap slots.
+pub fn destroy_address_space(space: AddressSpace) {
+    space.table.destroy(&mut GlobalFrameAllocator);
+}
+
+/// Map an anonymous kernel region of `size` bytes with an unmapped
+/// guard page right below it, returning the start of the region.
+/// Neighbouring guarded regions are thus always a guard page apart.
+pub fn map_guarded(size: usize, flags: VmaFlags, name: &'static str) -> Option<VirtualAddress> {
+    with_kernel_vmas(|vmas, table| {
+        let mut allocator = GlobalFrameAllocator;
+        let start = match vmas.map_guarded(size, flags, name) {
+            Ok(start) => start,
+            Err(_) => return None,
+        };
+        // Kernel stacks live here, which must never fault
+        match vmas.populate(start, table, &mut allocator) {
+            Ok(()) => Some(start),
+            Err(_) => {
+                vmas.unmap(start - PAGE_SIZE, size + PAGE_SIZE, table, &mut allocator).unwrap();
+                None
+            }
+        }
+    })
+}
+
+/// Unmap a region made by `map_guarded` along with its guard page.
+pub fn unmap_guarded(start: VirtualAddress, size: usize) {
+    with_kernel_vmas(|vmas, table| {
+        vmas.unmap(start - PAGE_SIZE, size + PAGE_SIZE, table, &mut GlobalFrameAllocator)
+            .expect("Unmapping a guarded region that is not mapped");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::page::tests::{HostFrames, host_table};
+
+    const START: VirtualAddress = 0x1000_0000;
+    const END: VirtualAddress = 0x2000_0000;
+
+    fn map_at(vmas: &mut VmaManager, address: VirtualAddress, pages: usize, backing: Backing)
+              -> Result<VirtualAddress, VmaError> {
+        vmas.map(Some(address), pages * PAGE_SIZE, VMA_READ | VMA_WRITE, backing,
+                 Sharing::Private, "test")
+    }
+
+    fn bounds(vmas: &VmaManager) -> Vec<(VirtualAddress, VirtualAddress)> {
+        vmas.regions().map(|vma| (vma.start, vma.end)).collect()
+    }
+
+    #[test]
+    fn map_and_find() {
+        let mut vmas = VmaManager::new(START, END);
+        assert_eq!(map_at(&mut vmas, START + 4 * PAGE_SIZE, 2, Backing::Anonymous),
+                   Ok(START + 4 * PAGE_SIZE));
+        assert!(vmas.find(START + 3 * PAGE_SIZE).is_none());
+        assert_eq!(vmas.find(START + 4 * PAGE_SIZE).unwrap().start, START + 4 * PAGE_SIZE);
+        assert_eq!(vmas.find(START + 6 * PAGE_SIZE - 1).unwrap().start, START + 4 * PAGE_SIZE);
+        assert!(vmas.find(START + 6 * PAGE_SIZE).is_none());
+        assert!(vmas.find(::core::usize::MAX).is_none());
+    }
+
+    #[test]
+    fn map_rejects_bad_ranges() {
+        let mut vmas = VmaManager::new(START, END);
+        map_at(&mut vmas, START + 4 * PAGE_SIZE, 2, Backing::Anonymous).unwrap();
+        assert_eq!(map_at(&mut vmas, START + 5 * PAGE_SIZE, 2, Backing::Anonymous),
+                   Err(VmaError::Overlap));
+        assert_eq!(map_at(&mut vmas, START + 3 * PAGE_SIZE, 2, Backing::Anonymous),
+                   Err(VmaError::Overlap));
+        assert_eq!(map_at(&mut vmas, START + 1, 1, Backing::Anonymous),
+                   Err(VmaError::Misaligned));
+        assert_eq!(map_at(&mut vmas, START, 0, Backing::Anonymous), Err(VmaError::Misaligned));
+        assert_eq!(map_at(&mut vmas, END - PAGE_SIZE, 2, Backing::Anonymous),
+                   Err(VmaError::OutOfRange));
+        assert_eq!(map_at(&mut vmas, START - PAGE_SIZE, 1, Backing::Anonymous),
+                   Err(VmaError::OutOfRange));
+        // Touching regions are fine
+        assert!(map_at(&mut vmas, START + 6 * PAGE_SIZE, 1, Backing::Anonymous).is_ok());
+        assert!(map_at(&mut vmas, START + 3 * PAGE_SIZE, 1, Backing::Anonymous).is_ok());
+    }
+
+    #[test]
+    fn find_free_fills_gaps_in_order() {
+        let mut vmas = VmaManager::new(START, END);
+        map_at(&mut vmas, START, 1, Backing::Anonymous).unwrap();
+        map_at(&mut vmas, START + 3 * PAGE_SIZE, 1, Backing::Anonymous).unwrap();
+        assert_eq!(vmas.find_free(2 * PAGE_SIZE, PAGE_SIZE), Some(START + PAGE_SIZE));
+        assert_eq!(vmas.find_free(3 * PAGE_SIZE, PAGE_SIZE), Some(START + 4 * PAGE_SIZE));
+        assert_eq!(vmas.find_free(PAGE_SIZE, 4 * PAGE_SIZE), Some(START + 4 * PAGE_SIZE));
+        assert_eq!(vmas.find_free(END - START, PAGE_SIZE), None);
+
+        let placed = vmas.map(None, 2 * PAGE_SIZE, VMA_READ, Backing::Anonymous,
+                              Sharing::Private, "test");
+        assert_eq!(placed, Ok(START + PAGE_SIZE));
+        assert_eq!(vmas.map(None, END - START, VMA_READ, Backing::Anonymous,
+                            Sharing::Private, "test"),
+                   Err(VmaError::NoSpace));
+    }
+
+    #[test]
+    fn split_range_cuts_straddling_regions() {
+        let mut vmas = VmaManager::new(START, END);
+        map_at(&mut vmas, START, 4, Backing::Anonymous).unwrap();
+        map_at(&mut vmas, START + 4 * PAGE_SIZE, 4, Backing::File { inode: 7, offset: 0 })
+            .unwrap();
+
+        let keys = vmas.split_range(START + PAGE_SIZE, START + 6 * PAGE_SIZE);
+        assert_eq!(keys, vec![START + PAGE_SIZE, START + 4 * PAGE_SIZE]);
+        assert_eq!(bounds(&vmas), vec![
+            (START, START + PAGE_SIZE),
+            (START + PAGE_SIZE, START + 4 * PAGE_SIZE),
+            (START + 4 * PAGE_SIZE, START + 6 * PAGE_SIZE),
+            (START + 6 * PAGE_SIZE, START + 8 * PAGE_SIZE),
+        ]);
+        // The upper part of a file region starts further into the file
+        assert_eq!(vmas.find(START + 6 * PAGE_SIZE).unwrap().backing,
+                   Backing::File { inode: 7, offset: 2 * PAGE_SIZE });
+
+        // Splitting at existing boundaries changes nothing
+        assert_eq!(vmas.split_range(START, START + PAGE_SIZE), vec![START]);
+        assert_eq!(bounds(&vmas).len(), 4);
+    }
+
+    #[test]
+    fn split_range_over_a_gap() {
+        let mut vmas = VmaManager::new(START, END);
+        map_at(&mut vmas, START + 2 * PAGE_SIZE, 2, Backing::Anonymous).unwrap();
+        assert!(vmas.split_range(START, START + 2 * PAGE_SIZE).is_empty());
+        assert_eq!(vmas.split_range(START, START + 3 * PAGE_SIZE), vec![START + 2 * PAGE_SIZE]);
+        assert_eq!(bounds(&vmas), vec![
+            (START + 2 * PAGE_SIZE, START + 3 * PAGE_SIZE),
+            (START + 3 * PAGE_SIZE, START + 4 * PAGE_SIZE),
+        ]);
+    }
+
+    #[test]
+    fn guarded_regions_stay_a_guard_page_apart() {
+        let mut vmas = VmaManager::new(START, END);
+        let first = vmas.map_guarded(2 * PAGE_SIZE, VMA_READ | VMA_WRITE, "test").unwrap();
+        let second = vmas.map_guarded(PAGE_SIZE, VMA_READ | VMA_WRITE, "test").unwrap();
+        assert_eq!(first, START + PAGE_SIZE);
+        assert_eq!(second, first + 3 * PAGE_SIZE);
+
+        let guard = vmas.find(second - PAGE_SIZE).unwrap();
+        assert_eq!(guard.backing, Backing::Reserved);
+        assert!(guard.flags.is_empty());
+        assert_eq!(vmas.find(second).unwrap().backing, Backing::Anonymous);
+        assert_eq!(vmas.map_guarded(PAGE_SIZE + 1, VMA_READ, "test"), Err(VmaError::Misaligned));
+        assert_eq!(vmas.map_guarded(END - START, VMA_READ, "test"), Err(VmaError::NoSpace));
+    }
+
+    #[test]
+    fn entry_flags_follow_region_flags() {
+        let flags = (VMA_READ | VMA_WRITE | VMA_USER).entry_flags();
+        assert!(flags.contains(PRESENT | WRITABLE | USER_ACCESSIBLE | NO_EXECUTE));
+        let flags = (VMA_READ | VMA_EXEC).entry_flags();
+        assert!(!flags.intersects(WRITABLE | USER_ACCESSIBLE | NO_EXECUTE));
+    }
+
+    #[test]
+    fn unmap_leaves_frames_of_reserved_regions() {
+        let mut frames = HostFrames { freed: Vec::new() };
+        let mut table = host_table(&mut frames);
+        let mut vmas = VmaManager::new(START, END);
+        let reserved = map_at(&mut vmas, START, 1, Backing::Reserved).unwrap();
+        let anonymous = map_at(&mut vmas, START + PAGE_SIZE, 1, Backing::Anonymous).unwrap();
+        // Device memory, which the frame allocator does not own
+        table.map_to(Page::from(reserved), Frame { number: 0xfee00 }, WRITABLE, &mut frames);
+        table.map(Page::from(anonymous), WRITABLE, &mut frames);
+        let frame = table.translate_page(Page::from(anonymous)).unwrap();
+
+        vmas.unmap(START, 2 * PAGE_SIZE, &mut table, &mut frames).unwrap();
+        assert!(table.translate(reserved).is_none());
+        assert!(table.translate(anonymous).is_none());
+        assert!(frames.freed.contains(&frame.number));
+        assert!(!frames.freed.contains(&0xfee00));
+        assert!(vmas.regions().next().is_none());
+    }
+}
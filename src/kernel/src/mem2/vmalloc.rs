@@ -0,0 +1,31 @@
+/*  Virtually contiguous kernel allocation module
+ *  Author: Andrew Jianzhong Liu
+ *  All rights reserved
+ */
+
+use super::*;
+use super::vma::*;
+
+const VMALLOC_NAME: &'static str = "vmalloc";
+
+/// Allocate `size` bytes of zeroed kernel memory that is contiguous in
+/// virtual memory only, so that no contiguous run of frames is needed.
+/// Each allocation has an unmapped guard page below it.
+pub fn vmalloc(size: usize) -> Option<VirtualAddress> {
+    if size == 0 {
+        return None;
+    }
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    map_guarded(size, VMA_READ | VMA_WRITE, VMALLOC_NAME)
+}
+
+/// Unmap a `vmalloc` allocation and release its frames.
+pub fn vfree(address: VirtualAddress) {
+    let size = with_kernel_vmas(|vmas, _| {
+        match vmas.find(address) {
+            Some(vma) if vma.start == address && vma.name == VMALLOC_NAME => vma.size(),
+            _ => panic!("vfree of {:#x}, which vmalloc did not return", address),
+        }
+    });
+    unmap_guarded(address, size);
+}
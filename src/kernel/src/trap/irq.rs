@@ -0,0 +1,119 @@
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::{Idt, ExceptionStackFrame, HandlerFunc};
+use dev::pic::{self, PIC_1_OFFSET, IRQ_COUNT};
+use dev::apic::{self, SPURIOUS_VECTOR};
+use util::without_interrupts;
+
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const COM2_IRQ: u8 = 3;
+pub const COM1_IRQ: u8 = 4;
+
+/// Called on each interrupt of the line it is registered for, with
+/// interrupts disabled. The end of interrupt is sent after it returns.
+pub type IrqHandler = fn(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    NoSuchLine,
+    /// The line has a handler already.
+    InUse,
+}
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+static SPURIOUS_IRQS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// The stack frame does not tell the vector, so each line gets an entry
+macro_rules! irq_entries {
+    ($($name:ident => $irq:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+        const IRQ_ENTRIES: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    }
+}
+
+irq_entries!(irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3, irq4 => 4, irq5 => 5, irq6 => 6,
+             irq7 => 7, irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11, irq12 => 12,
+             irq13 => 13, irq14 => 14, irq15 => 15);
+
+extern "x86-interrupt" fn apic_spurious(_stack_frame: &mut ExceptionStackFrame) {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Point the vectors the interrupt controllers raise at the dispatcher.
+pub fn set_irq_entries(idt: &mut Idt) {
+    for (irq, &entry) in IRQ_ENTRIES.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + irq].set_handler_fn(entry);
+    }
+    idt[SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious);
+}
+
+fn dispatch_irq(irq: u8) {
+    let apic = apic::apic_enabled();
+    let spurious = if apic { apic::check_spurious(irq) } else { pic::check_spurious(irq) };
+    if spurious {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Copied out, so that the handler may register others
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler(irq);
+    }
+    if apic {
+        apic::end_of_interrupt();
+    }
+    else {
+        pic::end_of_interrupt(irq);
+    }
+}
+
+fn mask_line(irq: u8) {
+    if apic::apic_enabled() { apic::mask_irq(irq) } else { pic::mask_irq(irq) }
+}
+
+fn unmask_line(irq: u8) {
+    if apic::apic_enabled() { apic::unmask_irq(irq) } else { pic::unmask_irq(irq) }
+}
+
+/// Handle `irq` with `handler` and unmask the line.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::NoSuchLine);
+    }
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err(IrqError::InUse);
+        }
+        handlers[irq as usize] = Some(handler);
+        Ok(())
+    })?;
+    unmask_line(irq);
+    Ok(())
+}
+
+/// Mask `irq` and drop its handler.
+pub fn unregister_irq(irq: u8) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::NoSuchLine);
+    }
+    mask_line(irq);
+    without_interrupts(|| IRQ_HANDLERS.lock()[irq as usize] = None);
+    Ok(())
+}
+
+/// Number of spurious interrupts, seen on IRQ 7 and IRQ 15 of the PICs
+/// or on the spurious vector of the local APIC.
+pub fn spurious_irqs() -> usize {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Let the CPU take hardware interrupts.
+pub fn enable_interrupts() {
+    unsafe { asm!("sti" :::: "volatile") };
+}
@@ -1,15 +1,26 @@
 mod gdt;
+mod irq;
 
 use x86_64::structures::idt::Idt;
-use mem::MemoryController;
+use mem2::MemoryManager;
 use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::structures::idt::{PageFaultErrorCode, PROTECTION_VIOLATION, CAUSED_BY_WRITE,
+                              USER_MODE, MALFORMED_TABLE, INSTRUCTION_FETCH};
+use x86_64::registers::control_regs;
+use mem2;
 use x86_64::instructions::interrupts::*;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtualAddress;
 use x86_64::instructions::segmentation::set_cs;
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::SegmentSelector;
+use dev;
 
+pub use self::irq::{IrqHandler, IrqError, register_irq, unregister_irq, spurious_irqs,
+                    enable_interrupts, TIMER_IRQ, KEYBOARD_IRQ, COM1_IRQ, COM2_IRQ};
+
+// A kernel stack overflow faults again pushing the page fault frame,
+// so the double fault handler gets a stack of its own to report it from
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
 use spin::Once;
@@ -25,6 +36,8 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
         }
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        irq::set_irq_entries(&mut idt);
         idt
     };
 }
@@ -38,13 +51,44 @@ extern "x86-interrupt" fn breakpoint_handler(
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut ExceptionStackFrame, _error_code: u64)
 {
-    println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // CR2 still holds the address of the page fault that could not be delivered
+    let address = control_regs::cr2().0;
+    match mem2::guard_owner(address) {
+        Some(owner) => println!("\nEXCEPTION: kernel stack overflow in {} at {:#x}\n{:#?}",
+                                owner, address, stack_frame),
+        None => println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame),
+    }
     loop {}
 }
 
-pub fn init_trap(memory_controller: &mut MemoryController) {
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode)
+{
+    let fault = mem2::PageFault {
+        address: control_regs::cr2().0,
+        protection_violation: error_code.contains(PROTECTION_VIOLATION),
+        write: error_code.contains(CAUSED_BY_WRITE),
+        user: error_code.contains(USER_MODE),
+        reserved_bit: error_code.contains(MALFORMED_TABLE),
+        instruction_fetch: error_code.contains(INSTRUCTION_FETCH),
+    };
+    match mem2::handle_page_fault(&fault) {
+        Ok(()) => {}
+        Err(mem2::FaultError::StackOverflow(owner)) => {
+            println!("\nEXCEPTION: kernel stack overflow in {}\n    {}\n{:#?}", owner, fault, stack_frame);
+            loop {}
+        }
+        Err(err) => {
+            println!("\nEXCEPTION: PAGE FAULT at {}\n    {:?}\n{:#?}", fault, err, stack_frame);
+            loop {}
+        }
+    }
+}
+
+pub fn init_trap(memory_manager: &MemoryManager) {
 
-    let double_fault_stack = memory_controller.alloc_stack(1)
+    // The IST stack is never freed
+    let double_fault_stack = memory_manager.alloc_stack(1, "double fault handler")
         .expect("could not allocate double fault stack");
 
     let tss = TSS.call_once(|| {
@@ -70,7 +114,9 @@ pub fn init_trap(memory_controller: &mut MemoryController) {
         // load TSS
         load_tss(tss_selector);
     }
-    
+
+    // Interrupts stay disabled until enable_interrupts, so the IDT goes
+    // live first and the PICs are remapped with every handler in place
     IDT.load();
-    
+    dev::pic::init_pic();
 }
\ No newline at end of file
@@ -18,4 +18,38 @@ pub fn enable_write_protect_bit() {
     use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
 
     unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
-}
\ No newline at end of file
+}
+
+// Unit tests run on the host in user mode, where page tables are plain
+// memory and the interrupt flag is not ours to change. The helpers
+// below leave the interrupt flag and the TLB alone there.
+
+/// Run `f` with interrupts disabled, for code taking locks that
+/// interrupt handlers take too. The interrupt flag is restored after.
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+    if cfg!(test) {
+        return f();
+    }
+    let rflags: u64;
+    unsafe { asm!("pushfq; popq $0; cli" : "=r"(rflags) ::: "volatile") };
+    let result = f();
+    // Interrupt enable flag
+    if rflags & (1 << 9) != 0 {
+        unsafe { asm!("sti" :::: "volatile") };
+    }
+    result
+}
+
+/// Drop the TLB entry of the page at `address`.
+pub fn flush_tlb(address: usize) {
+    if !cfg!(test) {
+        ::x86_64::instructions::tlb::flush(::x86_64::VirtualAddress(address));
+    }
+}
+
+/// Drop every TLB entry that is not global.
+pub fn flush_tlb_all() {
+    if !cfg!(test) {
+        ::x86_64::instructions::tlb::flush_all();
+    }
+}